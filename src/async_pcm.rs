@@ -0,0 +1,185 @@
+use crate::audio::Error;
+use alsa::pcm::State;
+use alsa::poll::{pollfd, Flags};
+use alsa::{PollDescriptors, PCM};
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::task::Poll;
+use tokio::io::unix::{AsyncFd, AsyncFdReadyGuard};
+use tokio::io::Interest;
+
+/// A non-blocking PCM whose poll descriptors are registered to the tokio reactor.
+///
+/// Readiness of the descriptors is translated by the PCM, so plugins like `dmix`
+/// which poll a timer instead of the device work too.
+pub struct AsyncPcm {
+    // dropped before the PCM closes the descriptors
+    fds: Vec<AsyncFd<PollFd>>,
+    /// the descriptors as the PCM gave them, for their events
+    descriptors: Vec<pollfd>,
+    pcm: PCM,
+    xruns: u64,
+    /// frames read or written by the last call before an xrun interrupted it
+    interrupted_at: usize,
+}
+
+struct PollFd(RawFd);
+
+impl AsRawFd for PollFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl AsyncPcm {
+    /// Registers the PCM. The PCM must be opened in non-blocking mode.
+    pub fn new(pcm: PCM) -> Result<Self, Error> {
+        let descriptors = pcm.get()?;
+        if descriptors.is_empty() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "PCM has no poll descriptors",
+            )));
+        }
+        let fds = descriptors
+            .iter()
+            .map(|descriptor| AsyncFd::with_interest(PollFd(descriptor.fd), interest(descriptor)))
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            fds,
+            descriptors,
+            pcm,
+            xruns: 0,
+            interrupted_at: 0,
+        })
+    }
+
+    /// Waits until the PCM is ready for `direction` or has an error,
+    /// returning the guards of the descriptors which were ready.
+    async fn ready(&self, direction: Flags) -> Result<Vec<AsyncFdReadyGuard<'_, PollFd>>, Error> {
+        loop {
+            let mut guards = std::future::poll_fn(|cx| {
+                let mut guards = Vec::new();
+                for (fd, descriptor) in self.fds.iter().zip(&self.descriptors) {
+                    let ready = if interest(descriptor) == Interest::WRITABLE {
+                        fd.poll_write_ready(cx)
+                    } else {
+                        fd.poll_read_ready(cx)
+                    };
+                    match ready {
+                        Poll::Ready(Ok(guard)) => guards.push((guard, descriptor.fd)),
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => {}
+                    }
+                }
+                if guards.is_empty() {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(Ok(guards))
+                }
+            })
+            .await?;
+
+            // the PCM tells what the events of its descriptors mean for the stream
+            let mut descriptors = self.descriptors.clone();
+            for descriptor in &mut descriptors {
+                if guards.iter().any(|(_, fd)| *fd == descriptor.fd) {
+                    descriptor.revents = descriptor.events;
+                }
+            }
+            let revents = self.pcm.revents(&descriptors)?;
+            if revents.intersects(direction | Flags::ERR | Flags::HUP) {
+                return Ok(guards.into_iter().map(|(guard, _)| guard).collect());
+            }
+            for (guard, _) in &mut guards {
+                guard.clear_ready();
+            }
+        }
+    }
+
+    /// The number of overruns or underruns recovered so far.
+    pub fn xruns(&self) -> u64 {
+        self.xruns
+    }
+
+    /// The frames read or written before the last [Error::Xrun].
+    pub fn interrupted_at(&self) -> usize {
+        self.interrupted_at
+    }
+
+    /// Reads until `buf` is filled and returns the number of frames read.
+    ///
+    /// If an overrun happens, the PCM is recovered and [Error::Xrun] is returned.
+    /// The content of `buf` is undefined in that case.
+    pub async fn readi(&mut self, buf: &mut [i16], channels: u32) -> Result<usize, Error> {
+        // the capture does not start until it's requested
+        if self.pcm.state() == State::Prepared {
+            self.pcm.start()?;
+        }
+
+        let channels = channels as usize;
+        let mut filled = 0;
+        while filled < buf.len() {
+            let mut guards = self.ready(Flags::IN).await?;
+            let result = self.pcm.io_i16()?.readi(&mut buf[filled..]);
+            match result {
+                Err(e) if would_block(&e) => guards.iter_mut().for_each(|x| x.clear_ready()),
+                Err(e) => {
+                    drop(guards);
+                    self.interrupted_at = filled / channels;
+                    return Err(self.recover(e));
+                }
+                Ok(read) => filled += read * channels,
+            }
+        }
+        Ok(filled / channels)
+    }
+
+    /// Writes whole `buf` and returns the number of frames written.
+    ///
+    /// If an underrun happens, the PCM is recovered and [Error::Xrun] is returned.
+    /// The frames written before it are told by [Self::interrupted_at].
+    pub async fn writei(&mut self, buf: &[i16], channels: u32) -> Result<usize, Error> {
+        let channels = channels as usize;
+        let mut written = 0;
+        while written < buf.len() {
+            let mut guards = self.ready(Flags::OUT).await?;
+            let result = self.pcm.io_i16()?.writei(&buf[written..]);
+            match result {
+                Err(e) if would_block(&e) => guards.iter_mut().for_each(|x| x.clear_ready()),
+                Err(e) => {
+                    drop(guards);
+                    self.interrupted_at = written / channels;
+                    return Err(self.recover(e));
+                }
+                Ok(wrote) => written += wrote * channels,
+            }
+        }
+        Ok(written / channels)
+    }
+
+    /// Recovers from xrun (EPIPE) or suspend (ESTRPIPE), which leaves the PCM prepared.
+    /// Other errors cannot be recovered and are returned as is.
+    fn recover(&mut self, e: alsa::Error) -> Error {
+        match self.pcm.try_recover(e, true) {
+            Ok(()) => {
+                self.xruns += 1;
+                Error::Xrun
+            }
+            Err(e) => Error::Alsa(e),
+        }
+    }
+}
+
+/// How a descriptor is watched, by the events the PCM polls it for
+fn interest(descriptor: &pollfd) -> Interest {
+    if descriptor.events & Flags::OUT.bits() != 0 {
+        Interest::WRITABLE
+    } else {
+        Interest::READABLE
+    }
+}
+
+fn would_block(e: &alsa::Error) -> bool {
+    io::Error::from_raw_os_error(e.errno()).kind() == io::ErrorKind::WouldBlock
+}
@@ -1,8 +1,29 @@
+use crate::opus_encoder::strerror;
+use opus::Channels;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The sample rate Opus is encoded and decoded with.
+/// RTP Opus always uses 48 kHz clock (RFC 7587 section 4.1) so we follow it.
+pub const OPUS_SAMPLE_RATE: u32 = 48000;
+
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum Error {
     Alsa(alsa::Error),
     Opus(opus::Error),
+    Io(std::io::Error),
+    /// overrun or underrun of the PCM, already recovered
+    Xrun,
+    UnsupportedChannels(u32),
+    /// error code returned from a libopus function called directly
+    Libopus(&'static str, i32),
+    InvalidFrameDuration(String),
+    Wav(hound::Error),
+    Ogg(ogg::OggReadError),
+    /// the file is not a valid Ogg Opus file
+    InvalidOggOpus(&'static str),
+    AudioProcessing(webrtc_audio_processing::Error),
 }
 
 impl std::fmt::Display for Error {
@@ -10,6 +31,21 @@ impl std::fmt::Display for Error {
         match self {
             Error::Alsa(e) => std::fmt::Display::fmt(e, f),
             Error::Opus(e) => std::fmt::Display::fmt(e, f),
+            Error::Io(e) => std::fmt::Display::fmt(e, f),
+            Error::Xrun => f.write_str("PCM overrun or underrun"),
+            Error::UnsupportedChannels(channels) => write!(
+                f,
+                "{channels} channels are not supported, only mono and stereo are supported"
+            ),
+            Error::Libopus(function, code) => write!(f, "{function}: {}", strerror(*code)),
+            Error::InvalidFrameDuration(value) => write!(
+                f,
+                "invalid frame duration: {value} (must be 2.5, 5, 10, 20, 40, 60, 80, 100 or 120 ms)"
+            ),
+            Error::Wav(e) => std::fmt::Display::fmt(e, f),
+            Error::Ogg(e) => std::fmt::Display::fmt(e, f),
+            Error::InvalidOggOpus(reason) => write!(f, "invalid Ogg Opus file: {reason}"),
+            Error::AudioProcessing(e) => std::fmt::Display::fmt(e, f),
         }
     }
 }
@@ -26,11 +62,129 @@ impl From<opus::Error> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<hound::Error> for Error {
+    fn from(value: hound::Error) -> Self {
+        Self::Wav(value)
+    }
+}
+
+impl From<ogg::OggReadError> for Error {
+    fn from(value: ogg::OggReadError) -> Self {
+        Self::Ogg(value)
+    }
+}
+
+impl From<webrtc_audio_processing::Error> for Error {
+    fn from(value: webrtc_audio_processing::Error) -> Self {
+        Self::AudioProcessing(value)
+    }
+}
+
 impl std::error::Error for Error {
     fn cause(&self) -> Option<&dyn std::error::Error> {
         match self {
             Error::Alsa(x) => Some(x),
             Error::Opus(x) => Some(x),
+            Error::Io(x) => Some(x),
+            Error::Xrun => None,
+            Error::UnsupportedChannels(_) => None,
+            Error::Libopus(_, _) => None,
+            Error::InvalidFrameDuration(_) => None,
+            Error::Wav(x) => Some(x),
+            Error::Ogg(x) => Some(x),
+            Error::InvalidOggOpus(_) => None,
+            Error::AudioProcessing(x) => Some(x),
+        }
+    }
+}
+
+/// What to do with the audio interrupted by an overrun or underrun
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum XrunPolicy {
+    /// Discard the interrupted audio
+    Drop,
+    /// Keep the timeline: capture sends silence in place of the lost frame,
+    /// playback plays the interrupted frame after the silence
+    Conceal,
+}
+
+/// Opus on WebRTC only supports mono and stereo (RFC 7587),
+/// so other channel counts are rejected here.
+pub fn opus_channels(channels: u32) -> Result<Channels, Error> {
+    match channels {
+        1 => Ok(Channels::Mono),
+        2 => Ok(Channels::Stereo),
+        _ => Err(Error::UnsupportedChannels(channels)),
+    }
+}
+
+/// The recommended size of the buffer for an encoded Opus packet.
+/// See the document of `opus_encode`.
+pub const MAX_PACKET_SIZE: usize = 4000;
+
+/// Duration of an Opus packet, one of the frame sizes Opus supports.
+///
+/// Opus frames are 2.5, 5, 10, 20, 40 or 60 ms.
+/// Longer packets up to 120 ms are made of multiple frames (RFC 6716 section 3.2).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FrameDuration {
+    /// samples per channel at 48 kHz
+    samples: u32,
+}
+
+impl FrameDuration {
+    const SINGLE_FRAME: [u32; 6] = [120, 240, 480, 960, 1920, 2880];
+    const MULTI_FRAME: [u32; 3] = [3840, 4800, 5760];
+
+    pub fn from_samples(samples: u32) -> Option<Self> {
+        if Self::SINGLE_FRAME.contains(&samples) || Self::MULTI_FRAME.contains(&samples) {
+            Some(Self { samples })
+        } else {
+            None
+        }
+    }
+
+    /// Samples per channel at `sample_rate`, rounded down.
+    pub fn samples(self, sample_rate: u32) -> usize {
+        (self.samples as u64 * sample_rate as u64 / OPUS_SAMPLE_RATE as u64) as usize
+    }
+
+    pub fn duration(self) -> Duration {
+        Duration::from_secs(self.samples as u64) / OPUS_SAMPLE_RATE
+    }
+
+    /// The number of Opus frames in a packet and the duration of each frame.
+    pub fn frames(self) -> (usize, FrameDuration) {
+        let frame = match self.samples {
+            // 80 ms = 2 x 40 ms, 100 ms = 5 x 20 ms, 120 ms = 2 x 60 ms
+            3840 => 1920,
+            4800 => 960,
+            5760 => 2880,
+            single => single,
+        };
+        (
+            (self.samples / frame) as usize,
+            FrameDuration { samples: frame },
+        )
+    }
+}
+
+impl FromStr for FrameDuration {
+    type Err = Error;
+
+    /// Parses the duration in milliseconds like `20` or `2.5`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let millis = f64::from_str(s).map_err(|_| Error::InvalidFrameDuration(s.to_owned()))?;
+        let samples = millis * OPUS_SAMPLE_RATE as f64 / 1000.0;
+        if samples.fract() != 0.0 || samples < 0.0 || samples > u32::MAX as f64 {
+            return Err(Error::InvalidFrameDuration(s.to_owned()));
         }
+        Self::from_samples(samples as u32).ok_or_else(|| Error::InvalidFrameDuration(s.to_owned()))
     }
 }
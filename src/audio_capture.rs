@@ -0,0 +1,101 @@
+use crate::async_pcm::AsyncPcm;
+use crate::audio::{opus_channels, Error, FrameDuration, XrunPolicy};
+use crate::audio_io::AudioSource;
+use crate::audio_processing::AudioProcessor;
+use crate::opus_encoder::{OpusEncoderConfig, PacketEncoder};
+use crate::voice_activity::AudioLevel;
+use alsa::pcm::{Access, Format, HwParams};
+use alsa::{Direction, ValueOr, PCM};
+use std::time::Duration;
+
+pub struct AudioCapture {
+    pcm: AsyncPcm,
+    capture_buffer: Vec<i16>,
+    packet_encoder: PacketEncoder,
+    channels: u32,
+    xrun_policy: XrunPolicy,
+}
+
+impl AudioCapture {
+    pub fn new(
+        name: &str,
+        sample_rate: u32,
+        channels: u32,
+        encoder_config: &OpusEncoderConfig,
+        frame_duration: FrameDuration,
+        xrun_policy: XrunPolicy,
+        audio_processor: Option<AudioProcessor>,
+    ) -> Result<Self, Error> {
+        opus_channels(channels)?;
+
+        let pcm = PCM::new(name, Direction::Capture, true)?;
+        {
+            let params = HwParams::any(&pcm).unwrap();
+            params.set_rate_resample(true)?;
+            params.set_access(Access::RWInterleaved)?;
+            params.set_channels(channels)?;
+            params.set_rate(sample_rate, ValueOr::Nearest)?;
+            params.set_format(Format::s16())?;
+            pcm.hw_params(&params)?;
+        }
+        // the device may not support the requested rate
+        let device_rate = pcm.hw_params_current()?.get_rate()?;
+
+        pcm.prepare()?;
+        let pcm = AsyncPcm::new(pcm)?;
+
+        let device_frame_samples = frame_duration.samples(device_rate);
+        let capture_buffer = vec![0i16; device_frame_samples * channels as usize];
+        let mut packet_encoder =
+            PacketEncoder::new(device_rate, channels, encoder_config, frame_duration)?;
+        if let Some(audio_processor) = audio_processor {
+            packet_encoder.set_audio_processor(audio_processor);
+        }
+        Ok(Self {
+            pcm,
+            capture_buffer,
+            packet_encoder,
+            channels,
+            xrun_policy,
+        })
+    }
+
+    /// Reads a frame at the device rate and returns the number of frames read.
+    async fn read_device(&mut self) -> Result<usize, Error> {
+        // https://github.com/diwic/alsa-rs/issues/111
+        // readi counts frames, each of which holds one sample per channel
+        loop {
+            match self
+                .pcm
+                .readi(&mut self.capture_buffer, self.channels)
+                .await
+            {
+                Err(Error::Xrun) => {
+                    println!("audio capture overrun ({} so far)", self.pcm.xruns());
+                    if self.xrun_policy == XrunPolicy::Conceal {
+                        self.capture_buffer.fill(0);
+                        return Ok(self.capture_buffer.len() / self.channels as usize);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl AudioSource for AudioCapture {
+    async fn capture_frame(&mut self) -> Result<(Duration, &[u8]), Error> {
+        while !self.packet_encoder.is_ready() {
+            let read = self.read_device().await?;
+            let buffer = &self.capture_buffer[..read * self.channels as usize];
+            self.packet_encoder.push(buffer)?;
+        }
+
+        let duration = self.packet_encoder.frame_duration().duration();
+        Ok((duration, self.packet_encoder.encode_packet()?))
+    }
+
+    fn audio_level(&self) -> Option<AudioLevel> {
+        Some(self.packet_encoder.audio_level())
+    }
+}
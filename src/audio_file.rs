@@ -0,0 +1,362 @@
+//! Audio sources and sinks of files, to run the audio path without sound hardware.
+//!
+//! Sources are paced in real time and loop at the end of the file.
+
+use crate::audio::{opus_channels, Error, FrameDuration, OPUS_SAMPLE_RATE};
+use crate::audio_io::{AudioSink, AudioSource, PcmSink};
+use crate::opus_encoder::{OpusEncoderConfig, PacketEncoder};
+use crate::voice_activity::AudioLevel;
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+
+/// Whether the file at `path` should be handled as Ogg Opus rather than WAV.
+pub fn is_ogg_opus(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|x| x.to_str()),
+        Some("ogg" | "opus" | "oga")
+    )
+}
+
+/// Sleeps until the time the next packet should be sent.
+struct Pacer {
+    deadline: Option<Instant>,
+}
+
+impl Pacer {
+    fn new() -> Self {
+        Self { deadline: None }
+    }
+
+    async fn wait(&mut self, duration: Duration) {
+        let deadline = self.deadline.get_or_insert_with(Instant::now);
+        tokio::time::sleep_until(*deadline).await;
+        *deadline += duration;
+    }
+}
+
+/// Encodes 16-bit PCM WAV file.
+pub struct WavSource {
+    /// interleaved samples with the channels of the encoder
+    samples: Vec<i16>,
+    position: usize,
+    packet_encoder: PacketEncoder,
+    pacer: Pacer,
+}
+
+impl WavSource {
+    pub fn open(
+        path: &Path,
+        channels: u32,
+        encoder_config: &OpusEncoderConfig,
+        frame_duration: FrameDuration,
+    ) -> Result<Self, Error> {
+        opus_channels(channels)?;
+        let mut reader = WavReader::open(path)?;
+        let spec = reader.spec();
+        if spec.sample_format != SampleFormat::Int || spec.bits_per_sample != 16 {
+            return Err(Error::Wav(hound::Error::Unsupported));
+        }
+        let samples = reader.samples::<i16>().collect::<Result<Vec<_>, _>>()?;
+        let samples = remix(samples, spec.channels as u32, channels)?;
+
+        Ok(Self {
+            samples,
+            position: 0,
+            packet_encoder: PacketEncoder::new(
+                spec.sample_rate,
+                channels,
+                encoder_config,
+                frame_duration,
+            )?,
+            pacer: Pacer::new(),
+        })
+    }
+}
+
+impl AudioSource for WavSource {
+    async fn capture_frame(&mut self) -> Result<(Duration, &[u8]), Error> {
+        let duration = self.packet_encoder.frame_duration().duration();
+        self.pacer.wait(duration).await;
+
+        // push a chunk at a time until a packet is filled
+        while !self.packet_encoder.is_ready() {
+            if self.samples.is_empty() {
+                self.packet_encoder.push(&[0; 960])?;
+                continue;
+            }
+            if self.position >= self.samples.len() {
+                self.position = 0;
+            }
+            let end = (self.position + 960).min(self.samples.len());
+            self.packet_encoder
+                .push(&self.samples[self.position..end])?;
+            self.position = end;
+        }
+
+        Ok((duration, self.packet_encoder.encode_packet()?))
+    }
+
+    fn audio_level(&self) -> Option<AudioLevel> {
+        Some(self.packet_encoder.audio_level())
+    }
+}
+
+/// Converts mono to stereo by duplicating, or stereo to mono by averaging.
+fn remix(samples: Vec<i16>, from: u32, to: u32) -> Result<Vec<i16>, Error> {
+    match (from, to) {
+        (from, to) if from == to => Ok(samples),
+        (1, 2) => Ok(samples.iter().flat_map(|&x| [x, x]).collect()),
+        (2, 1) => Ok(samples
+            .chunks_exact(2)
+            .map(|x| ((x[0] as i32 + x[1] as i32) / 2) as i16)
+            .collect()),
+        (from, _) => Err(Error::UnsupportedChannels(from)),
+    }
+}
+
+/// Writes audio into 16-bit PCM WAV file at 48 kHz.
+pub struct WavSink {
+    writer: WavWriter<BufWriter<File>>,
+}
+
+impl WavSink {
+    pub fn create(path: &Path, channels: u32) -> Result<Self, Error> {
+        let spec = WavSpec {
+            channels: channels as u16,
+            sample_rate: OPUS_SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        Ok(Self {
+            writer: WavWriter::create(path, spec)?,
+        })
+    }
+}
+
+impl PcmSink for WavSink {
+    async fn write_pcm(&mut self, pcm: &[i16]) -> Result<(), Error> {
+        for &sample in pcm {
+            self.writer.write_sample(sample)?;
+        }
+        // update the header so that the file is valid even if we're killed
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Sends packets in Ogg Opus file (RFC 7845) as is.
+pub struct OggOpusSource {
+    /// audio packets without the header packets
+    packets: Vec<Vec<u8>>,
+    position: usize,
+    pacer: Pacer,
+}
+
+impl OggOpusSource {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let mut reader = PacketReader::new(BufReader::new(File::open(path)?));
+        let mut packets = vec![];
+        while let Some(packet) = reader.read_packet()? {
+            packets.push(packet.data);
+        }
+
+        if !packets.first().is_some_and(|x| x.starts_with(b"OpusHead")) {
+            return Err(Error::InvalidOggOpus("OpusHead not found"));
+        }
+        if !packets.get(1).is_some_and(|x| x.starts_with(b"OpusTags")) {
+            return Err(Error::InvalidOggOpus("OpusTags not found"));
+        }
+        packets.drain(..2);
+        if packets.is_empty() {
+            return Err(Error::InvalidOggOpus("no audio packets"));
+        }
+
+        Ok(Self {
+            packets,
+            position: 0,
+            pacer: Pacer::new(),
+        })
+    }
+}
+
+impl AudioSource for OggOpusSource {
+    async fn capture_frame(&mut self) -> Result<(Duration, &[u8]), Error> {
+        if self.position >= self.packets.len() {
+            self.position = 0;
+        }
+        let packet = &self.packets[self.position];
+        self.position += 1;
+
+        let duration = packet_samples(packet)
+            .map(|samples| Duration::from_secs(samples as u64) / OPUS_SAMPLE_RATE)
+            .ok_or(Error::InvalidOggOpus("malformed Opus packet"))?;
+        self.pacer.wait(duration).await;
+
+        Ok((duration, packet))
+    }
+}
+
+/// Stores received packets as is in Ogg Opus file (RFC 7845).
+///
+/// The stream is ended when the sink is dropped.
+pub struct OggOpusSink {
+    writer: PacketWriter<BufWriter<File>>,
+    serial: u32,
+    /// samples at 48 kHz written so far
+    granule_position: u64,
+    stereo: bool,
+    packets_in_page: usize,
+    /// the last packet and its granule position, held back to end the stream with
+    last: Option<(Vec<u8>, u64)>,
+}
+
+/// Flush a page every second of 20 ms packets.
+const PACKETS_PER_PAGE: usize = 50;
+/// A packet which can't be parsed is taken as this long.
+const MALFORMED_PACKET_DURATION: Duration = Duration::from_millis(20);
+
+impl OggOpusSink {
+    pub fn create(path: &Path, channels: u32) -> Result<Self, Error> {
+        opus_channels(channels)?;
+        let mut writer = PacketWriter::new(BufWriter::new(File::create(path)?));
+        let serial = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |x| x.subsec_nanos());
+
+        let mut head = b"OpusHead".to_vec();
+        head.push(1); // version
+        head.push(channels as u8);
+        head.extend_from_slice(&0u16.to_le_bytes()); // pre-skip
+        head.extend_from_slice(&OPUS_SAMPLE_RATE.to_le_bytes());
+        head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+        head.push(0); // channel mapping family
+        writer.write_packet(
+            head.into_boxed_slice(),
+            serial,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+
+        let vendor = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor.as_bytes());
+        tags.extend_from_slice(&0u32.to_le_bytes()); // user comments
+        writer.write_packet(
+            tags.into_boxed_slice(),
+            serial,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+
+        Ok(Self {
+            writer,
+            serial,
+            granule_position: 0,
+            stereo: channels == 2,
+            packets_in_page: 0,
+            last: None,
+        })
+    }
+
+    fn write(&mut self, packet: Vec<u8>, samples: u32) -> Result<(), Error> {
+        self.granule_position += samples as u64;
+        let Some((last, granule_position)) = self.last.replace((packet, self.granule_position))
+        else {
+            return Ok(());
+        };
+        self.packets_in_page += 1;
+        let end = if self.packets_in_page >= PACKETS_PER_PAGE {
+            self.packets_in_page = 0;
+            PacketWriteEndInfo::EndPage
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        self.writer
+            .write_packet(last.into_boxed_slice(), self.serial, end, granule_position)?;
+        Ok(())
+    }
+
+    /// Writes the last packet on the last page of the stream.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        if let Some((last, granule_position)) = self.last.take() {
+            self.writer.write_packet(
+                last.into_boxed_slice(),
+                self.serial,
+                PacketWriteEndInfo::EndStream,
+                granule_position,
+            )?;
+        }
+        self.writer.inner_mut().flush()?;
+        Ok(())
+    }
+
+    /// Fills the gap with packets without any frame data, which decoders conceal.
+    fn write_gap(&mut self, duration: Duration) -> Result<(), Error> {
+        let mut remaining = (duration.as_micros() * OPUS_SAMPLE_RATE as u128 / 1_000_000) as u32;
+        // CELT fullband 20, 10, 5 and 2.5 ms
+        for (config, samples) in [(31, 960), (30, 480), (29, 240), (28, 120)] {
+            while remaining >= samples {
+                let toc = config << 3 | (self.stereo as u8) << 2;
+                self.write(vec![toc], samples)?;
+                remaining -= samples;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for OggOpusSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            println!("failed to end the Ogg Opus file: {e}");
+        }
+    }
+}
+
+impl AudioSink for OggOpusSink {
+    async fn play_frame(&mut self, encoded: &[u8]) -> Result<Duration, Error> {
+        let Some(samples) = packet_samples(encoded) else {
+            // the recording goes on with a gap in place of the packet
+            self.write_gap(MALFORMED_PACKET_DURATION)?;
+            return Ok(MALFORMED_PACKET_DURATION);
+        };
+        self.write(encoded.to_vec(), samples)?;
+        Ok(Duration::from_secs(samples as u64) / OPUS_SAMPLE_RATE)
+    }
+
+    async fn conceal_frame(&mut self, duration: Duration) -> Result<(), Error> {
+        self.write_gap(duration)
+    }
+
+    async fn recover_frame(&mut self, _next: &[u8], duration: Duration) -> Result<(), Error> {
+        // the FEC data stays in the next packet, which is written next
+        self.write_gap(duration)
+    }
+}
+
+/// The number of samples at 48 kHz in the packet, from the TOC byte (RFC 6716 section 3.1).
+fn packet_samples(packet: &[u8]) -> Option<u32> {
+    let toc = *packet.first()?;
+    let config = toc >> 3;
+    let frame_samples = match config {
+        // SILK 10, 20, 40, 60 ms
+        0..=11 => [480, 960, 1920, 2880][config as usize % 4],
+        // Hybrid 10, 20 ms
+        12..=15 => [480, 960][config as usize % 2],
+        // CELT 2.5, 5, 10, 20 ms
+        _ => [120, 240, 480, 960][config as usize % 4],
+    };
+    let frames = match toc & 0b11 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => (*packet.get(1)? & 0b0011_1111) as u32,
+    };
+    FrameDuration::from_samples(frame_samples * frames)?;
+    Some(frame_samples * frames)
+}
@@ -0,0 +1,52 @@
+use crate::audio::Error;
+use crate::voice_activity::AudioLevel;
+use std::future::Future;
+use std::time::Duration;
+
+/// Where Opus packets sent to the remote peer come from.
+///
+/// Implemented for sound devices ([crate::audio_capture::AudioCapture])
+/// and files ([crate::audio_file]).
+pub trait AudioSource: Send {
+    /// Waits for the next packet and returns the duration of the audio in it and the packet.
+    fn capture_frame(&mut self) -> impl Future<Output = Result<(Duration, &[u8]), Error>> + Send;
+
+    /// The audio level and voice activity of the last packet, if the source knows the audio.
+    fn audio_level(&self) -> Option<AudioLevel> {
+        None
+    }
+}
+
+/// Where Opus packets received from the remote peer go to as is.
+///
+/// Implemented for Ogg Opus files ([crate::audio_file::OggOpusSink]).
+/// Decoded audio goes to [PcmSink] through [crate::audio_mixer::AudioMixer].
+pub trait AudioSink: Send {
+    /// Plays a packet and returns the duration of the audio in it.
+    fn play_frame(
+        &mut self,
+        encoded: &[u8],
+    ) -> impl Future<Output = Result<Duration, Error>> + Send;
+
+    /// Fills the lost packet of `duration` with packet loss concealment.
+    fn conceal_frame(
+        &mut self,
+        duration: Duration,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Fills the lost packet of `duration` with in-band FEC data in the next packet.
+    fn recover_frame(
+        &mut self,
+        next: &[u8],
+        duration: Duration,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+/// Where audio of the remote peer goes to after decoding and mixing.
+///
+/// Implemented for sound devices ([crate::audio_playback::AudioPlayback])
+/// and WAV files ([crate::audio_file::WavSink]).
+pub trait PcmSink: Send {
+    /// Plays interleaved PCM at 48 kHz.
+    fn write_pcm(&mut self, pcm: &[i16]) -> impl Future<Output = Result<(), Error>> + Send;
+}
@@ -0,0 +1,254 @@
+//! Mixes audio tracks of the remote peer into one output.
+//!
+//! Each track has its own jitter buffer and decoder. Its RTP timeline is mapped onto the
+//! mixer's sample clock from the timestamp and arrival of its first packet, delayed by the
+//! playout delay shared by the tracks, so that tracks keep their relative timing while
+//! they're mixed. A frame is placed by its timestamp, so silence of DTX stays silent,
+//! and a frame coming too late for its place delays the track instead of being dropped.
+
+use crate::audio::{Error, OPUS_SAMPLE_RATE};
+use crate::audio_io::PcmSink;
+use crate::audio_processing::AudioProcessor;
+use crate::jitter_buffer::{JitterBuffer, Playout};
+use crate::opus_decoder::PacketDecoder;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The mixer outputs 10 ms at a time.
+const MIX_DURATION: Duration = Duration::from_millis(10);
+
+/// A gap in the timestamps longer than this is taken as a reset of the timeline
+/// instead of played as silence.
+const MAX_GAP: Duration = Duration::from_secs(1);
+
+/// Mixed audio louder than this fraction of full scale is compressed instead of clipped.
+const LIMITER_KNEE: f32 = 0.8;
+
+#[derive(Clone)]
+pub struct AudioMixer {
+    inputs: Arc<Mutex<Vec<MixerInput>>>,
+    channels: u32,
+}
+
+/// The sample clock of the mixer at 48 kHz
+struct MixerClock {
+    start: Instant,
+    /// frames mixed so far
+    position: u64,
+    /// playout delay of the tracks from arrival (frames), the largest one of a track yet
+    delay: u64,
+}
+
+struct MixerInput {
+    /// shared with [MixerInputHandle], which is dropped when the track ends
+    jitter_buffer: Arc<Mutex<JitterBuffer>>,
+    clock_rate: u32,
+    packet_decoder: PacketDecoder,
+    gain: f32,
+    /// decoded interleaved samples at 48 kHz not mixed yet
+    pending: VecDeque<i16>,
+    /// the position of the first pending frame on the mixer clock
+    pending_position: u64,
+    /// the position of the first timestamp of the track on the mixer clock, once playing
+    origin_position: Option<u64>,
+}
+
+/// Feeds received packets of a track to the mixer. The track is removed when this is dropped.
+pub struct MixerInputHandle {
+    jitter_buffer: Arc<Mutex<JitterBuffer>>,
+}
+
+impl MixerInputHandle {
+    pub fn push(&self, sequence_number: u16, timestamp: u32, payload: Vec<u8>, arrival: Instant) {
+        self.jitter_buffer
+            .lock()
+            .unwrap()
+            .push(sequence_number, timestamp, payload, arrival);
+    }
+}
+
+impl AudioMixer {
+    /// Creates a mixer which outputs `channels` of interleaved PCM at 48 kHz.
+    pub fn new(channels: u32) -> Self {
+        Self {
+            inputs: Arc::new(Mutex::new(Vec::new())),
+            channels,
+        }
+    }
+
+    /// Adds a track whose packets are received through the returned handle.
+    pub fn add_input(
+        &self,
+        jitter_buffer: JitterBuffer,
+        clock_rate: u32,
+        gain: f32,
+    ) -> Result<MixerInputHandle, Error> {
+        let jitter_buffer = Arc::new(Mutex::new(jitter_buffer));
+        let input = MixerInput {
+            jitter_buffer: jitter_buffer.clone(),
+            clock_rate,
+            packet_decoder: PacketDecoder::new(OPUS_SAMPLE_RATE, self.channels)?,
+            gain,
+            pending: VecDeque::new(),
+            pending_position: 0,
+            origin_position: None,
+        };
+        self.inputs.lock().unwrap().push(input);
+        Ok(MixerInputHandle { jitter_buffer })
+    }
+
+    /// Mixes the tracks into `output` forever, with the pace of the audio.
+    ///
+    /// The mixed audio is fed to `audio_processor` as the far-end reference of echo cancellation.
+    pub async fn run(
+        &self,
+        mut output: impl PcmSink,
+        mut audio_processor: Option<AudioProcessor>,
+    ) -> Result<(), Error> {
+        let channels = self.channels as usize;
+        let frame_len = (OPUS_SAMPLE_RATE as usize / 100) * channels;
+        let mut mixed = vec![0f32; frame_len];
+        let mut limited = vec![0i16; frame_len];
+
+        let mut deadline = tokio::time::Instant::now();
+        let mut clock = MixerClock {
+            start: deadline.into_std(),
+            position: 0,
+            delay: 0,
+        };
+        loop {
+            mixed.fill(0.0);
+            {
+                let mut inputs = self.inputs.lock().unwrap();
+                // the receiving task drops the handle when the track ends
+                inputs.retain(|input| {
+                    Arc::strong_count(&input.jitter_buffer) > 1 || !input.pending.is_empty()
+                });
+                for input in inputs.iter_mut() {
+                    input.fill(&mut clock, frame_len / channels, channels);
+                    // an input still buffering or not at its place yet contributes silence
+                    let offset = input.pending_position.saturating_sub(clock.position) as usize;
+                    let Some(room) = frame_len.checked_sub(offset * channels) else {
+                        continue;
+                    };
+                    let len = input.pending.len().min(room);
+                    for (mixed, sample) in mixed[offset * channels..]
+                        .iter_mut()
+                        .zip(input.pending.drain(..len))
+                    {
+                        *mixed += sample as f32 * input.gain;
+                    }
+                    input.pending_position += (len / channels) as u64;
+                }
+            }
+            clock.position += (frame_len / channels) as u64;
+
+            for (output, &mixed) in limited.iter_mut().zip(&mixed) {
+                *output = limit(mixed);
+            }
+            if let Some(audio_processor) = &mut audio_processor {
+                audio_processor.push_render(&limited)?;
+            }
+            output.write_pcm(&limited).await?;
+
+            deadline += MIX_DURATION;
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+impl MixerInput {
+    /// Decodes frames from the jitter buffer until the next `frames` of the mixer are covered
+    /// or it runs dry, placing them on the mixer clock by their timestamps.
+    ///
+    /// A frame which can't be decoded is dropped, so a corrupt packet of one track
+    /// doesn't stop the mix. The next frame is placed by its timestamp.
+    fn fill(&mut self, clock: &mut MixerClock, frames: usize, channels: usize) {
+        // nothing is placed before the mixer position
+        if self.pending.is_empty() {
+            self.pending_position = self.pending_position.max(clock.position);
+        }
+        let end = |input: &Self| input.pending_position + (input.pending.len() / channels) as u64;
+        while end(self) < clock.position + frames as u64 {
+            let (playout, timestamp, origin) = {
+                let mut jitter_buffer = self.jitter_buffer.lock().unwrap();
+                let playout = jitter_buffer.pop();
+                (
+                    playout,
+                    jitter_buffer.last_timestamp(),
+                    jitter_buffer.origin(),
+                )
+            };
+            let Some(playout) = playout else {
+                break;
+            };
+            if let (Some(timestamp), Some(origin)) = (timestamp, origin) {
+                self.place(clock, timestamp, origin, end(self), channels);
+            }
+            let pcm = match playout {
+                Playout::Packet(packet) => self.packet_decoder.decode(&packet).map(|x| x.1),
+                Playout::Fec { next, samples } => {
+                    let duration = Duration::from_secs(samples as u64) / self.clock_rate;
+                    self.packet_decoder.recover(&next, duration)
+                }
+                Playout::Conceal { samples } => {
+                    let duration = Duration::from_secs(samples as u64) / self.clock_rate;
+                    self.packet_decoder.conceal(duration)
+                }
+            };
+            match pcm {
+                Ok(pcm) => self.pending.extend(pcm),
+                Err(e) => println!("dropping an audio frame of a track: {e}"),
+            }
+        }
+    }
+
+    /// Pads the pending samples with silence up to the place of the frame with `timestamp`,
+    /// or delays the track if the frame is late for its place.
+    fn place(
+        &mut self,
+        clock: &mut MixerClock,
+        timestamp: u32,
+        (first_timestamp, first_arrival): (u32, Instant),
+        end: u64,
+        channels: usize,
+    ) {
+        // frames on the mixer clock from the first timestamp, negative if reordered before it
+        let elapsed = timestamp.wrapping_sub(first_timestamp) as i32 as i64
+            * OPUS_SAMPLE_RATE as i64
+            / self.clock_rate as i64;
+        let origin_position = *self.origin_position.get_or_insert_with(|| {
+            // the first frame plays now, and the delay from its arrival is shared with
+            // the tracks which start later, while this track keeps it only if it's larger
+            let first_arrival = first_arrival.saturating_duration_since(clock.start);
+            let first_arrival = (first_arrival.as_secs_f64() * OPUS_SAMPLE_RATE as f64) as i64;
+            let delay = (end as i64 - first_arrival - elapsed).max(0) as u64;
+            clock.delay = clock.delay.max(delay);
+            first_arrival as u64 + clock.delay
+        });
+        let place = (origin_position as i64 + elapsed).max(0) as u64;
+        let max_gap = (MAX_GAP.as_secs_f64() * OPUS_SAMPLE_RATE as f64) as u64;
+        if place > end && place - end <= max_gap {
+            let silence = (place - end) as usize * channels;
+            self.pending.extend(std::iter::repeat(0).take(silence));
+        } else if place != end {
+            // late, overlapping or a reset of the timeline: the track continues from here
+            self.origin_position = Some((end as i64 - elapsed).max(0) as u64);
+        }
+    }
+}
+
+/// Soft-clips a mixed sample so that loud overlapping tracks are compressed
+/// smoothly instead of wrapping around or clipping hard.
+fn limit(sample: f32) -> i16 {
+    let full_scale = i16::MAX as f32;
+    let knee = LIMITER_KNEE * full_scale;
+    let magnitude = sample.abs();
+    let limited = if magnitude <= knee {
+        magnitude
+    } else {
+        knee + (full_scale - knee) * ((magnitude - knee) / (full_scale - knee)).tanh()
+    };
+    limited.copysign(sample) as i16
+}
@@ -0,0 +1,96 @@
+use crate::async_pcm::AsyncPcm;
+use crate::audio::{opus_channels, Error, XrunPolicy, OPUS_SAMPLE_RATE};
+use crate::audio_io::PcmSink;
+use crate::resampler::Resampler;
+
+use alsa::pcm::{Access, Format, HwParams};
+use alsa::{ValueOr, PCM};
+
+pub struct AudioPlayback {
+    pcm: AsyncPcm,
+    resampler: Resampler,
+    /// samples resampled to the device rate
+    resampled: Vec<i16>,
+    channels: u32,
+    xrun_policy: XrunPolicy,
+}
+
+impl AudioPlayback {
+    pub fn new(
+        name: &str,
+        sample_rate: u32,
+        channels: u32,
+        xrun_policy: XrunPolicy,
+    ) -> Result<Self, Error> {
+        opus_channels(channels)?;
+
+        let pcm = PCM::new(name, alsa::Direction::Playback, true)?;
+        {
+            let params = HwParams::any(&pcm).unwrap();
+            params.set_channels(channels)?;
+            params.set_format(Format::s16())?;
+            params.set_rate(sample_rate, ValueOr::Nearest)?;
+            params.set_access(Access::RWInterleaved)?;
+            pcm.hw_params(&params)?;
+        }
+        // the device may not support the requested rate
+        let device_rate = pcm.hw_params_current()?.get_rate()?;
+        let pcm = AsyncPcm::new(pcm)?;
+
+        Ok(Self {
+            pcm,
+            resampler: Resampler::new(OPUS_SAMPLE_RATE, device_rate, channels),
+            resampled: Vec::new(),
+            channels,
+            xrun_policy,
+        })
+    }
+}
+
+impl PcmSink for AudioPlayback {
+    async fn write_pcm(&mut self, pcm: &[i16]) -> Result<(), Error> {
+        self.resampled.clear();
+        self.resampler.process(pcm, &mut self.resampled);
+        write(
+            &mut self.pcm,
+            &self.resampled,
+            self.channels,
+            self.xrun_policy,
+        )
+        .await
+    }
+}
+
+/// Writes interleaved PCM to the device.
+///
+/// Underruns are recovered however many happen, so only other errors are returned.
+async fn write(
+    pcm: &mut AsyncPcm,
+    buffer: &[i16],
+    channels: u32,
+    xrun_policy: XrunPolicy,
+) -> Result<(), Error> {
+    let mut remaining = buffer;
+    loop {
+        match pcm.writei(remaining, channels).await {
+            Err(Error::Xrun) => println!("audio playback underrun ({} so far)", pcm.xruns()),
+            result => return result.map(|_| ()),
+        }
+        // the frames written before the underrun were played or dropped by the recovery
+        remaining = &remaining[pcm.interrupted_at() * channels as usize..];
+        // start again with a frame of silence so that we won't underrun again at once
+        let silence = vec![0i16; buffer.len()];
+        loop {
+            match pcm.writei(&silence, channels).await {
+                Err(Error::Xrun) => println!("audio playback underrun ({} so far)", pcm.xruns()),
+                result => {
+                    result?;
+                    break;
+                }
+            }
+        }
+        if xrun_policy == XrunPolicy::Drop {
+            return Ok(());
+        }
+    }
+}
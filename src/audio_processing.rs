@@ -0,0 +1,139 @@
+//! Echo cancellation, noise suppression and automatic gain control of captured audio
+//! with the WebRTC audio processing module.
+//!
+//! The module works on 10 ms frames at 48 kHz, so it runs on the Opus side of the resamplers:
+//! captured audio is processed before encoding and mixed audio of the remote peer
+//! is fed as the far-end reference before it's resampled for the speaker.
+
+use crate::audio::Error;
+use webrtc_audio_processing::{
+    Config, EchoCancellation, EchoCancellationSuppressionLevel, GainControl, GainControlMode,
+    InitializationConfig, NoiseSuppression, Processor, NUM_SAMPLES_PER_FRAME,
+};
+
+/// How aggressively noise is suppressed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum NoiseSuppressionLevel {
+    Low,
+    Moderate,
+    High,
+    VeryHigh,
+}
+
+impl NoiseSuppressionLevel {
+    fn raw(self) -> webrtc_audio_processing::NoiseSuppressionLevel {
+        use webrtc_audio_processing::NoiseSuppressionLevel as Raw;
+        match self {
+            NoiseSuppressionLevel::Low => Raw::Low,
+            NoiseSuppressionLevel::Moderate => Raw::Moderate,
+            NoiseSuppressionLevel::High => Raw::High,
+            NoiseSuppressionLevel::VeryHigh => Raw::VeryHigh,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct AudioProcessingConfig {
+    pub echo_cancellation: bool,
+    pub noise_suppression: Option<NoiseSuppressionLevel>,
+    pub gain_control: bool,
+}
+
+impl AudioProcessingConfig {
+    /// Whether any processing is enabled
+    pub fn enabled(&self) -> bool {
+        self.echo_cancellation || self.noise_suppression.is_some() || self.gain_control
+    }
+}
+
+/// A handle of the audio processing module shared by capture and playback.
+///
+/// Give a clone to [crate::opus_encoder::PacketEncoder] to process captured audio,
+/// and another to [crate::audio_mixer::AudioMixer::run] to feed the far-end reference.
+#[derive(Clone)]
+pub struct AudioProcessor {
+    processor: Processor,
+    /// played samples at 48 kHz waiting for a 10 ms frame to be filled,
+    /// or the captured frame being processed
+    buffer: Vec<f32>,
+    capture_channels: usize,
+    render_channels: usize,
+}
+
+impl AudioProcessor {
+    /// Creates a processor for `capture_channels` of microphone
+    /// and `render_channels` of speaker.
+    pub fn new(
+        capture_channels: u32,
+        render_channels: u32,
+        config: &AudioProcessingConfig,
+    ) -> Result<Self, Error> {
+        let mut processor = Processor::new(&InitializationConfig {
+            num_capture_channels: capture_channels as i32,
+            num_render_channels: render_channels as i32,
+            ..Default::default()
+        })?;
+
+        processor.set_config(Config {
+            echo_cancellation: config.echo_cancellation.then_some(EchoCancellation {
+                suppression_level: EchoCancellationSuppressionLevel::High,
+                // the delay between the speaker and the microphone is unknown and
+                // changes with the buffering of both PCMs
+                stream_delay_ms: None,
+                enable_delay_agnostic: true,
+                enable_extended_filter: true,
+            }),
+            noise_suppression: config.noise_suppression.map(|level| NoiseSuppression {
+                suppression_level: level.raw(),
+            }),
+            gain_control: config.gain_control.then_some(GainControl {
+                mode: GainControlMode::AdaptiveDigital,
+                target_level_dbfs: 3,
+                compression_gain_db: 9,
+                enable_limiter: true,
+            }),
+            enable_high_pass_filter: true,
+            ..Default::default()
+        });
+
+        Ok(Self {
+            processor,
+            buffer: Vec::new(),
+            capture_channels: capture_channels as usize,
+            render_channels: render_channels as usize,
+        })
+    }
+
+    /// The number of interleaved samples in a 10 ms frame of captured audio
+    pub fn capture_frame_len(&self) -> usize {
+        NUM_SAMPLES_PER_FRAME as usize * self.capture_channels
+    }
+
+    /// Processes a 10 ms frame of captured interleaved PCM at 48 kHz in place.
+    pub fn process_capture_frame(&mut self, frame: &mut [i16]) -> Result<(), Error> {
+        debug_assert_eq!(frame.len(), self.capture_frame_len());
+        self.buffer.clear();
+        self.buffer
+            .extend(frame.iter().map(|&x| x as f32 / 32768.0));
+        self.processor.process_capture_frame(&mut self.buffer)?;
+        for (output, &processed) in frame.iter_mut().zip(&self.buffer) {
+            *output = (processed * 32768.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Feeds interleaved PCM at 48 kHz played on the speaker as the far-end reference.
+    pub fn push_render(&mut self, pcm: &[i16]) -> Result<(), Error> {
+        self.buffer.extend(pcm.iter().map(|&x| x as f32 / 32768.0));
+        let frame_len = NUM_SAMPLES_PER_FRAME as usize * self.render_channels;
+        let mut processed = 0;
+        while self.buffer.len() - processed >= frame_len {
+            let frame = &mut self.buffer[processed..processed + frame_len];
+            self.processor.process_render_frame(frame)?;
+            processed += frame_len;
+        }
+        self.buffer.drain(..processed);
+        Ok(())
+    }
+}
@@ -1,22 +1,99 @@
+use crate::camera_control::{Command, ControlInfo, Reply};
+use crate::motion_detection::{LumaView, MotionDetector, MotionEvent};
+use crate::overlay::Overlay;
+use crate::privacy_mask;
+use crate::privacy_mask::PrivacyMask;
+use crate::scaler::{scale, scaled_size, FrameLayout, V4l2Scaler};
+use crate::snapshot::{encode_jpeg, RawFrame, SnapshotReply};
 use std::io;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::io::unix::AsyncFd;
 use tokio::io::Interest;
+use tokio::sync::broadcast;
 use v4l::buffer::Type;
 use v4l::capability::Flags;
+use v4l::control;
+use v4l::control::{Control, Value};
 use v4l::device::MultiPlaneDevice;
 use v4l::format::MultiPlaneFormat;
+use v4l::framesize::FrameSizeEnum;
 use v4l::io::traits::{CaptureStream, OutputStream, Stream};
 use v4l::prelude::*;
 use v4l::video::{capture, output, Capture, Output};
 use v4l::{Format, FourCC};
 
+/// V4L2_CID_MPEG_VIDEO_FORCE_KEY_FRAME
+const FORCE_KEY_FRAME: u32 = 0x009909e5;
+/// V4L2_CID_MPEG_VIDEO_BITRATE
+const VIDEO_BITRATE: u32 = 0x009909cf;
+
 pub struct CameraCapture<'a> {
     camera_async_fd: AsyncFd<Arc<v4l::device::Handle>>,
-    encoder_async_fd: AsyncFd<Arc<v4l::device::Handle>>,
+    camera: Device,
+    /// the card name of the camera
+    camera_name: String,
+    /// encoders of the simulcast layers, the first one encodes frames at the captured size
+    encoders: Vec<Encoder>,
+    /// None only if reconfiguring failed
+    streams: Option<Streams<'a>>,
+    streaming: bool,
+    config: StreamConfig,
+    /// the format the camera captures in
+    camera_format: Format,
+    /// scales captured frames to the streaming size, if they differ
+    scaler: Option<Scaler<'a>>,
+    /// the last frame scaled to the streaming size
+    scaled: Vec<u8>,
+    /// snapshots to take from the next frame
+    snapshot_replies: Vec<SnapshotReply>,
+    motion: Option<Motion>,
+    overlay: Option<Overlay>,
+    privacy_masks: Vec<PrivacyMask>,
+}
+
+struct Encoder {
+    async_fd: AsyncFd<Arc<v4l::device::Handle>>,
+    device: MultiPlaneDevice,
+    /// frames are scaled down by this for the encoder
+    divisor: u32,
+}
+
+struct Streams<'a> {
     camera_stream: MmapStream<'a>,
-    encoder_raw_stream1: MmapStream<'a>,
-    encoder_encoded_stream1: MmapStream<'a>,
+    /// streams of each encoder
+    encoder_streams: Vec<EncoderStreams<'a>>,
+}
+
+struct EncoderStreams<'a> {
+    raw_stream1: MmapStream<'a>,
+    encoded_stream1: MmapStream<'a>,
+}
+
+#[derive(Copy, Clone)]
+struct StreamConfig {
+    width: u32,
+    height: u32,
+    fps: u32,
+    capture_buffer: u32,
+    /// the size the camera captures at, if other than the streaming size
+    capture_size: Option<(u32, u32)>,
+    camera_fourcc: [u8; 4],
+    /// the format the encoders take
+    stream_fourcc: [u8; 4],
+    encoded_fourcc: [u8; 4],
+}
+
+enum Scaler<'a> {
+    V4l2(V4l2Scaler<'a>),
+    Software,
+}
+
+struct Motion {
+    detector: MotionDetector,
+    events: broadcast::Sender<MotionEvent>,
+    /// start a new GOP when motion starts
+    keyframe: bool,
 }
 
 impl<'a> CameraCapture<'a> {
@@ -47,117 +124,708 @@ impl<'a> CameraCapture<'a> {
             ));
         }
 
-        Capture::set_format(
-            &mut camera,
-            &Format::new(width, height, FourCC::new(camera_fourcc)),
-        )?;
-        Capture::set_params(&mut camera, &capture::Parameters::with_fps(fps))?;
+        let mut encoders = vec![open_encoder(encoder_device, 1)?];
+
+        let config = StreamConfig {
+            width,
+            height,
+            fps,
+            capture_buffer,
+            capture_size: None,
+            camera_fourcc: *camera_fourcc,
+            stream_fourcc: *camera_fourcc,
+            encoded_fourcc: *encoded_fourcc,
+        };
+        let (camera_format, streams) = set_up(&mut camera, &mut encoders, &config)?;
 
-        let mut encoder = MultiPlaneDevice::new(encoder_device)?;
-        let encoder_async_fd = AsyncFd::new(encoder.handle())?;
+        Ok(Self {
+            camera_async_fd,
+            camera,
+            camera_name: camera_caps.card,
+            encoders,
+            streams: Some(streams),
+            streaming: false,
+            config,
+            camera_format,
+            scaler: None,
+            scaled: Vec::new(),
+            snapshot_replies: Vec::new(),
+            motion: None,
+            overlay: None,
+            privacy_masks: Vec::new(),
+        })
+    }
 
-        let encoder_caps = encoder.query_caps()?;
-        if !encoder_caps.capabilities.contains(Flags::VIDEO_M2M_MPLANE) {
+    /// Adds a simulcast layer encoded by `encoder_device` from frames scaled down by `divisor`.
+    ///
+    /// Devices like the encoder of a Pi can be opened once per layer.
+    pub fn add_layer(&mut self, encoder_device: usize, divisor: u32) -> io::Result<()> {
+        if self.streaming {
             return Err(io::Error::new(
-                io::ErrorKind::Unsupported,
-                "Encoder: M2M MPlane not supported",
+                io::ErrorKind::Other,
+                "Camera: can't add a layer while streaming",
             ));
         }
-        if !encoder_caps.capabilities.contains(Flags::STREAMING) {
+        self.check_maskable(&self.privacy_masks, &self.stream_layout().fourcc)?;
+        // lower layers are scaled in software
+        check_software_format(&self.stream_layout().fourcc)?;
+        let (width, height) = scaled_size(self.config.width, self.config.height, divisor);
+        if width == 0 || height == 0 {
             return Err(io::Error::new(
-                io::ErrorKind::Unsupported,
-                "Encoder: Streaming",
+                io::ErrorKind::InvalidInput,
+                "Camera: layer is smaller than a macroblock",
             ));
         }
+        let encoder = open_encoder(encoder_device, divisor)?;
+        // buffers are freed when the streams are dropped
+        self.streams = None;
+        self.encoders.push(encoder);
+        self.set_up_streams()
+    }
 
-        Output::set_format(
-            &mut encoder,
-            &MultiPlaneFormat::single_plane(width, height, FourCC::new(camera_fourcc)),
-        )?;
-        Capture::set_format(
-            &mut encoder,
-            &MultiPlaneFormat::single_plane(width, height, FourCC::new(encoded_fourcc)),
-        )?;
-        Output::set_params(&mut encoder, &output::Parameters::with_fps(fps))?;
+    /// Captures at `width`x`height` and scales frames to the streaming size for the encoders,
+    /// converting them to `stream_fourcc`.
+    ///
+    /// The M2M `scaler_device` is used if it can be opened and configured, otherwise frames
+    /// are scaled in software, which only takes YUYV and NV12 and can't convert them.
+    pub fn set_scaler(
+        &mut self,
+        width: u32,
+        height: u32,
+        stream_fourcc: &[u8; 4],
+        scaler_device: Option<usize>,
+    ) -> io::Result<()> {
+        if self.streaming {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Camera: can't add a scaler while streaming",
+            ));
+        }
+        let scaler = match scaler_device.map(V4l2Scaler::new) {
+            Some(Ok(scaler)) => Scaler::V4l2(scaler),
+            Some(Err(e)) => {
+                println!("scaler device not available, scaling in software: {e}");
+                Scaler::Software
+            }
+            None => Scaler::Software,
+        };
+        self.check_maskable(&self.privacy_masks, stream_fourcc)?;
+        if self.encoders.len() > 1 {
+            check_software_format(stream_fourcc)?;
+        }
+        if matches!(scaler, Scaler::Software) {
+            check_software_scaling(&self.config.camera_fourcc, stream_fourcc)?;
+        }
+
+        // buffers are freed when the streams are dropped
+        self.streams = None;
+        self.scaler = Some(scaler);
+        self.config.capture_size = Some((width, height));
+        self.config.stream_fourcc = *stream_fourcc;
+        self.set_up_streams()
+    }
 
-        let mut camera_stream =
-            MmapStream::with_buffers(&camera, Type::VideoCapture, capture_buffer)?;
-        let mut encoder_raw_stream1 =
-            MmapStream::with_buffers(&encoder, Type::VideoOutputMplane, 1)?;
-        let mut encoder_encoded_stream1 =
-            MmapStream::with_buffers(&encoder, Type::VideoCaptureMplane, 1)?;
+    /// Detects motion in captured frames and sends changes to `events`,
+    /// forcing a keyframe when motion starts if `keyframe` is set.
+    pub fn set_motion_detector(
+        &mut self,
+        detector: MotionDetector,
+        events: broadcast::Sender<MotionEvent>,
+        keyframe: bool,
+    ) {
+        self.motion = Some(Motion {
+            detector,
+            events,
+            keyframe,
+        });
+    }
+
+    /// Draws text into frames for the encoders.
+    pub fn set_overlay(&mut self, overlay: Overlay) {
+        self.overlay = Some(overlay);
+    }
 
-        for i in 0..capture_buffer {
-            CaptureStream::queue(&mut camera_stream, i as usize)?;
+    /// Masks regions of frames for the encoders and snapshots.
+    ///
+    /// Fails if the masks aren't within the picture or frames for the encoders can't be masked.
+    pub fn set_privacy_masks(&mut self, masks: Vec<PrivacyMask>) -> io::Result<()> {
+        privacy_mask::validate(&masks)?;
+        self.check_maskable(&masks, &self.stream_layout().fourcc)?;
+        self.privacy_masks = masks;
+        Ok(())
+    }
+
+    /// Fails if there are masks and frames of `fourcc` can't be masked,
+    /// so nothing is streamed unmasked.
+    fn check_maskable(&self, masks: &[PrivacyMask], fourcc: &[u8; 4]) -> io::Result<()> {
+        if masks.is_empty() || privacy_mask::can_mask(fourcc) {
+            return Ok(());
         }
-        OutputStream::queue(&mut encoder_raw_stream1, 0)?;
-        CaptureStream::queue(&mut encoder_encoded_stream1, 0)?;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "Camera: privacy masks need YUYV or NV12 frames, not {}",
+                String::from_utf8_lossy(fourcc)
+            ),
+        ))
+    }
 
-        Ok(Self {
-            camera_async_fd,
-            encoder_async_fd,
-            camera_stream,
-            encoder_raw_stream1,
-            encoder_encoded_stream1,
-        })
+    pub fn privacy_masks(&self) -> &[PrivacyMask] {
+        &self.privacy_masks
+    }
+
+    /// The card name of the camera
+    pub fn camera_name(&self) -> &str {
+        &self.camera_name
+    }
+
+    /// Runs a command of the control data channel.
+    pub fn execute(&mut self, command: Command) -> io::Result<Reply> {
+        match command {
+            Command::ListControls => {
+                return Ok(Reply {
+                    controls: Some(self.controls()?),
+                    ..Default::default()
+                })
+            }
+            Command::ListPrivacyMasks => {
+                return Ok(Reply {
+                    privacy_masks: Some(self.privacy_masks.clone()),
+                    ..Default::default()
+                })
+            }
+            Command::SetPrivacyMasks { masks } => self.set_privacy_masks(masks)?,
+            Command::SetControl { control, value } => self.set_control(control, value)?,
+            Command::Keyframe => self.force_keyframe()?,
+            Command::Bitrate { bitrate } => self.set_bitrate(bitrate)?,
+            Command::Reconfigure { width, height, fps } => {
+                self.reconfigure(width, height, fps.unwrap_or(self.config.fps))?
+            }
+            Command::SetOverlay {
+                texts,
+                shapes,
+                scale,
+                background,
+            } => {
+                self.overlay = (!texts.is_empty() || !shapes.is_empty())
+                    .then(|| Overlay::new(texts, shapes, &self.camera_name, scale, background))
+            }
+            Command::Trigger => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Camera: not a camera command",
+                ))
+            }
+        }
+        Ok(Reply::default())
+    }
+
+    /// The controls of the camera, like brightness, exposure, focus and pan/tilt/zoom.
+    pub fn controls(&self) -> io::Result<Vec<ControlInfo>> {
+        let mut controls = Vec::new();
+        for description in self.camera.query_controls()? {
+            if description.typ == control::Type::CtrlClass
+                || description.flags.contains(control::Flags::DISABLED)
+            {
+                continue;
+            }
+            let value = match description.typ {
+                control::Type::Button => None,
+                _ => match self.camera.control(description.id)?.value {
+                    Value::Integer(x) => Some(x),
+                    Value::Boolean(x) => Some(x as i64),
+                    _ => None,
+                },
+            };
+            controls.push(ControlInfo {
+                id: description.id,
+                name: description.name,
+                minimum: description.minimum,
+                maximum: description.maximum,
+                step: description.step,
+                default: description.default,
+                value,
+            });
+        }
+        Ok(controls)
+    }
+
+    pub fn set_control(&self, id: u32, value: i64) -> io::Result<()> {
+        let description = self
+            .camera
+            .query_controls()?
+            .into_iter()
+            .find(|x| x.id == id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Camera: no such control"))?;
+        let value = match description.typ {
+            control::Type::Boolean => Value::Boolean(value != 0),
+            control::Type::Button => Value::None,
+            _ => Value::Integer(value.clamp(description.minimum, description.maximum)),
+        };
+        self.camera.set_control(Control { id, value })
+    }
+
+    /// Changes the target bitrate of the full size layer (bit per second).
+    /// Smaller layers get a bitrate in proportion to their area.
+    pub fn set_bitrate(&self, bitrate: u32) -> io::Result<()> {
+        for encoder in &self.encoders {
+            encoder.device.set_control(Control {
+                id: VIDEO_BITRATE,
+                value: Value::Integer((bitrate / encoder.divisor.pow(2)) as i64),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Makes the encoders output a keyframe next.
+    pub fn force_keyframe(&self) -> io::Result<()> {
+        for encoder in &self.encoders {
+            encoder.device.set_control(Control {
+                id: FORCE_KEY_FRAME,
+                value: Value::Integer(1),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Takes a JPEG of the next frame captured and sends it to `reply`.
+    pub fn request_snapshot(&mut self, reply: SnapshotReply) {
+        self.snapshot_replies.push(reply);
     }
 }
 
 impl<'a> CameraCapture<'a> {
-    pub async fn take_frame(&mut self) -> io::Result<Vec<u8>> {
+    /// Captures a frame and returns an access unit of each layer.
+    pub async fn take_frame(&mut self) -> io::Result<Vec<Vec<u8>>> {
         let read_write: Interest = Interest::WRITABLE | Interest::READABLE;
+        let stream_layout = self.stream_layout();
+        let Some(streams) = &mut self.streams else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Camera: not configured",
+            ));
+        };
 
-        let index = self
-            .encoder_async_fd
-            .async_io(read_write, |_| {
-                OutputStream::dequeue(&mut self.encoder_raw_stream1)
-            })
-            .await?;
-        let (out_buffers, _meta, planes) = OutputStream::get(&mut self.encoder_raw_stream1, index)?;
+        let mut indexes = Vec::with_capacity(self.encoders.len());
+        for (encoder, encoder_streams) in self.encoders.iter().zip(&mut streams.encoder_streams) {
+            let index = encoder
+                .async_fd
+                .async_io(read_write, |_| {
+                    OutputStream::dequeue(&mut encoder_streams.raw_stream1)
+                })
+                .await?;
+            indexes.push(index);
+        }
 
         let cam_index = self
             .camera_async_fd
             .async_io(read_write, |_| {
-                CaptureStream::dequeue(&mut self.camera_stream)
+                CaptureStream::dequeue(&mut streams.camera_stream)
             })
             .await?;
         let (cam_buffers, cam_meta, _cam_planes) =
-            CaptureStream::get(&self.camera_stream, cam_index)?;
+            CaptureStream::get(&streams.camera_stream, cam_index)?;
         let cam_len = cam_meta.length;
         let cam_buffer = &cam_buffers[0][..cam_len as usize];
-        out_buffers[0][..cam_len as usize].copy_from_slice(cam_buffer);
-        CaptureStream::queue(&mut self.camera_stream, cam_index)?;
-
-        planes[0].bytesused = cam_len;
-        OutputStream::queue(&mut self.encoder_raw_stream1, index)?;
+        let layout = camera_layout(&self.camera_format);
+        // the frame for the encoders, at the streaming size
+        let (frame, frame_layout) = match &mut self.scaler {
+            None => (cam_buffer, layout),
+            Some(Scaler::Software) => {
+                self.scaled.resize(stream_layout.size(), 0);
+                scale(
+                    cam_buffer,
+                    &layout,
+                    stream_layout.width,
+                    stream_layout.height,
+                    &mut self.scaled,
+                )?;
+                (&self.scaled[..], stream_layout)
+            }
+            Some(Scaler::V4l2(scaler)) => {
+                scaler.scale(cam_buffer, &mut self.scaled).await?;
+                (&self.scaled[..], stream_layout)
+            }
+        };
+        let texts = self.overlay.as_ref().map(|overlay| overlay.render());
+        for ((encoder, encoder_streams), &index) in self
+            .encoders
+            .iter()
+            .zip(&mut streams.encoder_streams)
+            .zip(&indexes)
+        {
+            let (out_buffers, _meta, planes) =
+                OutputStream::get(&mut encoder_streams.raw_stream1, index)?;
+            let layer_layout = if encoder.divisor == 1 {
+                out_buffers[0][..frame.len()].copy_from_slice(frame);
+                planes[0].bytesused = frame.len() as u32;
+                frame_layout
+            } else {
+                let (width, height) =
+                    scaled_size(frame_layout.width, frame_layout.height, encoder.divisor);
+                planes[0].bytesused =
+                    scale(frame, &frame_layout, width, height, &mut out_buffers[0])? as u32;
+                FrameLayout::packed(width, height, frame_layout.fourcc)
+            };
+            // masked before the overlay, which may be on top of a mask.
+            // A frame which can't be masked isn't queued for the encoder
+            privacy_mask::apply(&self.privacy_masks, &mut out_buffers[0], &layer_layout)?;
+            if let (Some(overlay), Some(texts)) = (&self.overlay, &texts) {
+                overlay.draw(&mut out_buffers[0], &layer_layout, texts, encoder.divisor);
+            }
+            OutputStream::queue(&mut encoder_streams.raw_stream1, index)?;
+        }
+        if !self.snapshot_replies.is_empty() {
+            let mut data = cam_buffer.to_vec();
+            let masked = privacy_mask::apply(&self.privacy_masks, &mut data, &layout);
+            // like the frames of the encoders, at the size of the camera
+            if let (Some(overlay), Some(texts)) = (&self.overlay, &texts) {
+                overlay.draw(&mut data, &layout, texts, 1);
+            }
+            let frame = RawFrame {
+                data,
+                width: layout.width,
+                height: layout.height,
+                stride: layout.stride,
+                fourcc: layout.fourcc,
+            };
+            let replies = std::mem::take(&mut self.snapshot_replies);
+            // encoding takes longer than a frame interval
+            tokio::task::spawn_blocking(move || {
+                let jpeg = masked.and_then(|_| encode_jpeg(&frame));
+                for reply in replies {
+                    let jpeg = match &jpeg {
+                        Ok(jpeg) => Ok(jpeg.clone()),
+                        Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+                    };
+                    let _ = reply.send(jpeg);
+                }
+            });
+        }
+        let mut force_keyframe = false;
+        if let Some(motion) = &mut self.motion {
+            let luma = LumaView::of(
+                cam_buffer,
+                layout.width,
+                layout.height,
+                layout.stride,
+                &layout.fourcc,
+            );
+            if let Some(event) =
+                luma.and_then(|luma| motion.detector.analyze(&luma, Instant::now()))
+            {
+                force_keyframe = motion.keyframe && matches!(event, MotionEvent::Started { .. });
+                let _ = motion.events.send(event);
+            }
+        }
+        CaptureStream::queue(&mut streams.camera_stream, cam_index)?;
 
-        let index = self
-            .encoder_async_fd
-            .async_io(read_write, |_| {
-                CaptureStream::dequeue(&mut self.encoder_encoded_stream1)
-            })
-            .await?;
-        let (out_buffers, _meta, planes) =
-            CaptureStream::get(&self.encoder_encoded_stream1, index)?;
-        let buffer = Vec::from(&out_buffers[0][..planes[0].bytesused as usize]);
-        CaptureStream::queue(&mut self.encoder_encoded_stream1, index)?;
+        let mut access_units = Vec::with_capacity(self.encoders.len());
+        for (encoder, encoder_streams) in self.encoders.iter().zip(&mut streams.encoder_streams) {
+            let index = encoder
+                .async_fd
+                .async_io(read_write, |_| {
+                    CaptureStream::dequeue(&mut encoder_streams.encoded_stream1)
+                })
+                .await?;
+            let (out_buffers, _meta, planes) =
+                CaptureStream::get(&encoder_streams.encoded_stream1, index)?;
+            access_units.push(Vec::from(&out_buffers[0][..planes[0].bytesused as usize]));
+            CaptureStream::queue(&mut encoder_streams.encoded_stream1, index)?;
+        }
 
-        return Ok(buffer);
+        // applies to the next frame, as this one is encoded already
+        if force_keyframe {
+            self.force_keyframe()?;
+        }
+        return Ok(access_units);
     }
 }
 
 impl<'a> CameraCapture<'a> {
     pub fn start(&mut self) -> io::Result<()> {
-        self.camera_stream.start()?;
-        self.encoder_raw_stream1.start()?;
-        self.encoder_encoded_stream1.start()?;
+        if let Some(streams) = &mut self.streams {
+            streams.camera_stream.start()?;
+            for encoder_streams in &mut streams.encoder_streams {
+                encoder_streams.raw_stream1.start()?;
+                encoder_streams.encoded_stream1.start()?;
+            }
+        }
+        self.streaming = true;
         Ok(())
     }
 
     pub fn stop(&mut self) -> io::Result<()> {
-        self.camera_stream.stop()?;
-        self.encoder_raw_stream1.stop()?;
-        self.encoder_encoded_stream1.stop()?;
+        if let Some(streams) = &mut self.streams {
+            streams.camera_stream.stop()?;
+            for encoder_streams in &mut streams.encoder_streams {
+                encoder_streams.raw_stream1.stop()?;
+                encoder_streams.encoded_stream1.stop()?;
+            }
+        }
+        self.streaming = false;
+        Ok(())
+    }
+
+    /// Changes resolution and framerate while the session stays up.
+    ///
+    /// Formats can't change while buffers are allocated, so the streams are stopped and
+    /// freed first. The encoders restart with an IDR carrying the new SPS.
+    /// If the devices don't take the new configuration, the previous one is restored.
+    pub fn reconfigure(&mut self, width: u32, height: u32, fps: u32) -> io::Result<()> {
+        let streaming = self.streaming;
+        if streaming {
+            self.stop()?;
+        }
+        // buffers are freed when the streams are dropped
+        self.streams = None;
+
+        let previous = self.config;
+        self.config = StreamConfig {
+            width,
+            height,
+            fps,
+            ..previous
+        };
+        let result = self.set_up_streams();
+        if result.is_err() {
+            self.config = previous;
+            self.set_up_streams()?;
+        }
+
+        if streaming {
+            self.start()?;
+            self.force_keyframe()?;
+        }
+        // frames of different sizes aren't comparable
+        if let Some(motion) = &mut self.motion {
+            motion.detector.reset();
+        }
+        result
+    }
+
+    fn set_up_streams(&mut self) -> io::Result<()> {
+        let (camera_format, streams) = set_up(&mut self.camera, &mut self.encoders, &self.config)?;
+        // unscaled frames go to encoders of the requested size as they are
+        if self.scaler.is_none()
+            && (camera_format.width, camera_format.height)
+                != (self.config.width, self.config.height)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Camera: captures at {}x{}, not {}x{}",
+                    camera_format.width,
+                    camera_format.height,
+                    self.config.width,
+                    self.config.height
+                ),
+            ));
+        }
+        let camera_layout = camera_layout(&camera_format);
+        if let Some(Scaler::V4l2(scaler)) = &mut self.scaler {
+            let stream_layout = FrameLayout::packed(
+                self.config.width,
+                self.config.height,
+                self.config.stream_fourcc,
+            );
+            if let Err(e) = scaler.configure(&camera_layout, &stream_layout) {
+                println!("scaler device can't take these formats, scaling in software: {e}");
+                self.scaler = Some(Scaler::Software);
+            }
+        }
+        // the camera may not have taken the requested format
+        if let Some(Scaler::Software) = self.scaler {
+            check_software_scaling(&camera_layout.fourcc, &self.config.stream_fourcc)?;
+            // frames are scaled to pairs of pixels
+            if (self.config.width | self.config.height) & 1 != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Scaler: can't scale to odd sizes in software",
+                ));
+            }
+        }
+        self.camera_format = camera_format;
+        if self.encoders.len() > 1 {
+            check_software_format(&self.stream_layout().fourcc)?;
+        }
+        self.streams = Some(streams);
         Ok(())
     }
+
+    /// The layout of frames the encoders take
+    fn stream_layout(&self) -> FrameLayout {
+        let packed = FrameLayout::packed(
+            self.config.width,
+            self.config.height,
+            self.config.stream_fourcc,
+        );
+        match &self.scaler {
+            // the device may pad lines
+            Some(Scaler::V4l2(scaler)) => scaler.output_layout().unwrap_or(packed),
+            Some(Scaler::Software) => packed,
+            None => camera_layout(&self.camera_format),
+        }
+    }
+
+    /// The sizes frames can be streamed at, None if frames are scaled to any size.
+    ///
+    /// Sizes of a range are only listed for the aspect ratio of the streaming size.
+    pub fn stream_sizes(&self) -> io::Result<Option<Vec<(u32, u32)>>> {
+        if self.scaler.is_some() {
+            return Ok(None);
+        }
+        let (width, height) = (self.config.width, self.config.height);
+        let fourcc = FourCC::new(&self.config.camera_fourcc);
+        let mut sizes = Vec::new();
+        for frame_size in Capture::enum_framesizes(&self.camera, fourcc)? {
+            match frame_size.size {
+                FrameSizeEnum::Discrete(size) => sizes.push((size.width, size.height)),
+                FrameSizeEnum::Stepwise(steps) => {
+                    let widths = (steps.min_width..=steps.max_width)
+                        .step_by(steps.step_width.max(1) as usize);
+                    for step_width in widths {
+                        let step_height = step_width * height / width;
+                        if step_width * height == step_height * width
+                            && (steps.min_height..=steps.max_height).contains(&step_height)
+                            && (step_height - steps.min_height) % steps.step_height.max(1) == 0
+                        {
+                            sizes.push((step_width, step_height));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(Some(sizes))
+    }
+
+    pub fn width(&self) -> u32 {
+        self.config.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.config.height
+    }
+
+    pub fn fps(&self) -> u32 {
+        self.config.fps
+    }
+}
+
+fn camera_layout(format: &Format) -> FrameLayout {
+    FrameLayout {
+        width: format.width,
+        height: format.height,
+        stride: format.stride,
+        fourcc: format.fourcc.repr,
+    }
+}
+
+/// Fails if frames of `camera_fourcc` can't be scaled in software to `stream_fourcc`.
+fn check_software_scaling(camera_fourcc: &[u8; 4], stream_fourcc: &[u8; 4]) -> io::Result<()> {
+    if camera_fourcc != stream_fourcc {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Scaler: can't convert pixel formats in software",
+        ));
+    }
+    check_software_format(camera_fourcc)
+}
+
+/// Fails if frames of `fourcc` can't be scaled in software.
+fn check_software_format(fourcc: &[u8; 4]) -> io::Result<()> {
+    if !matches!(fourcc, b"YUYV" | b"NV12") {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Scaler: only YUYV and NV12 are supported in software",
+        ));
+    }
+    Ok(())
+}
+
+fn open_encoder(device: usize, divisor: u32) -> io::Result<Encoder> {
+    let device = MultiPlaneDevice::new(device)?;
+    let async_fd = AsyncFd::new(device.handle())?;
+
+    let caps = device.query_caps()?;
+    if !caps.capabilities.contains(Flags::VIDEO_M2M_MPLANE) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Encoder: M2M MPlane not supported",
+        ));
+    }
+    if !caps.capabilities.contains(Flags::STREAMING) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Encoder: Streaming",
+        ));
+    }
+
+    Ok(Encoder {
+        async_fd,
+        device,
+        divisor,
+    })
+}
+
+/// Sets the formats of camera and encoders and allocates their buffers.
+fn set_up<'a>(
+    camera: &mut Device,
+    encoders: &mut [Encoder],
+    config: &StreamConfig,
+) -> io::Result<(Format, Streams<'a>)> {
+    let StreamConfig {
+        width,
+        height,
+        fps,
+        capture_buffer,
+        capture_size,
+        camera_fourcc,
+        stream_fourcc,
+        encoded_fourcc,
+    } = *config;
+
+    let (capture_width, capture_height) = capture_size.unwrap_or((width, height));
+    let camera_format = Capture::set_format(
+        camera,
+        &Format::new(capture_width, capture_height, FourCC::new(&camera_fourcc)),
+    )?;
+    Capture::set_params(camera, &capture::Parameters::with_fps(fps))?;
+
+    let mut camera_stream = MmapStream::with_buffers(camera, Type::VideoCapture, capture_buffer)?;
+    for i in 0..capture_buffer {
+        CaptureStream::queue(&mut camera_stream, i as usize)?;
+    }
+
+    let mut encoder_streams = Vec::with_capacity(encoders.len());
+    for encoder in encoders {
+        let (width, height) = scaled_size(width, height, encoder.divisor);
+        let device = &mut encoder.device;
+        Output::set_format(
+            device,
+            &MultiPlaneFormat::single_plane(width, height, FourCC::new(&stream_fourcc)),
+        )?;
+        Capture::set_format(
+            device,
+            &MultiPlaneFormat::single_plane(width, height, FourCC::new(&encoded_fourcc)),
+        )?;
+        Output::set_params(device, &output::Parameters::with_fps(fps))?;
+
+        let mut raw_stream1 = MmapStream::with_buffers(device, Type::VideoOutputMplane, 1)?;
+        let mut encoded_stream1 = MmapStream::with_buffers(device, Type::VideoCaptureMplane, 1)?;
+        OutputStream::queue(&mut raw_stream1, 0)?;
+        CaptureStream::queue(&mut encoded_stream1, 0)?;
+        encoder_streams.push(EncoderStreams {
+            raw_stream1,
+            encoded_stream1,
+        });
+    }
+
+    Ok((
+        camera_format,
+        Streams {
+            camera_stream,
+            encoder_streams,
+        },
+    ))
 }
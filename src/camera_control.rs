@@ -0,0 +1,162 @@
+//! Command protocol of the control data channel.
+//!
+//! The viewer sends JSON requests like `{"id": 1, "command": "set_control", "control": 9963776,
+//! "value": 128}` and gets a response with the same id. Events like motion are sent unasked.
+//!
+//! The data channel is negotiated with id [CONTROL_CHANNEL_ID] and label `control`,
+//! so a browser opens it with `createDataChannel("control", {negotiated: true, id: 0})`.
+
+use crate::overlay::{OverlayShape, OverlayText};
+use crate::privacy_mask::PrivacyMask;
+use serde::{Deserialize, Serialize};
+use std::io;
+use tokio::sync::{mpsc, oneshot};
+
+pub const CONTROL_CHANNEL_LABEL: &str = "control";
+pub const CONTROL_CHANNEL_ID: u16 = 0;
+
+#[derive(Deserialize)]
+pub struct Request {
+    /// echoed in the response
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    /// V4L2 controls of the camera with their current values
+    ListControls,
+    SetControl {
+        control: u32,
+        value: i64,
+    },
+    Keyframe,
+    /// target bitrate of the video encoder (bit per second)
+    Bitrate {
+        bitrate: u32,
+    },
+    /// change resolution and framerate, keeping the framerate if not given
+    Reconfigure {
+        width: u32,
+        height: u32,
+        fps: Option<u32>,
+    },
+    /// privacy masks of the picture
+    ListPrivacyMasks,
+    /// replace the privacy masks, saved for the next start
+    SetPrivacyMasks {
+        masks: Vec<PrivacyMask>,
+    },
+    /// record an event when recording is triggered
+    Trigger,
+    /// replace the overlay texts and shapes, removed if both are empty. Sent on reload
+    /// of the configuration, not by the viewer
+    #[serde(skip)]
+    SetOverlay {
+        texts: Vec<OverlayText>,
+        shapes: Vec<OverlayShape>,
+        scale: u32,
+        background: bool,
+    },
+}
+
+#[derive(Serialize)]
+pub struct Response {
+    pub id: Option<u64>,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(flatten)]
+    pub reply: Reply,
+}
+
+/// What a command returns
+#[derive(Default, Serialize)]
+pub struct Reply {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub controls: Option<Vec<ControlInfo>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub privacy_masks: Option<Vec<PrivacyMask>>,
+}
+
+impl Response {
+    pub fn new(id: Option<u64>, result: io::Result<Reply>) -> Self {
+        match result {
+            Ok(reply) => Self {
+                id,
+                ok: true,
+                error: None,
+                reply,
+            },
+            Err(e) => Self {
+                id,
+                ok: false,
+                error: Some(e.to_string()),
+                reply: Reply::default(),
+            },
+        }
+    }
+}
+
+/// A V4L2 control of the camera
+#[derive(Serialize)]
+pub struct ControlInfo {
+    pub id: u32,
+    pub name: String,
+    pub minimum: i64,
+    pub maximum: i64,
+    pub step: u64,
+    pub default: i64,
+    /// None for controls without a value like buttons
+    pub value: Option<i64>,
+}
+
+/// Messages sent without a request
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    MotionStarted { zone: usize, area: f32 },
+    MotionStopped,
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "camera stopped")
+}
+
+pub type CameraReply = oneshot::Sender<io::Result<Reply>>;
+
+/// Sends commands to the task owning [crate::camera_capture::CameraCapture].
+#[derive(Clone)]
+pub struct CameraControlHandle {
+    sender: mpsc::Sender<(Command, CameraReply)>,
+}
+
+impl CameraControlHandle {
+    /// Creates a handle and the receiver to serve with the camera.
+    pub fn new() -> (Self, mpsc::Receiver<(Command, CameraReply)>) {
+        let (sender, receiver) = mpsc::channel(8);
+        (Self { sender }, receiver)
+    }
+
+    pub async fn execute(&self, command: Command) -> io::Result<Reply> {
+        let result = self.queue(command).await?;
+        result.await.map_err(|_| stopped())?
+    }
+
+    /// Queues a command for the camera, returning the receiver of its reply.
+    ///
+    /// The command is run when the camera gets to it, even if the reply isn't awaited.
+    pub async fn queue(
+        &self,
+        command: Command,
+    ) -> io::Result<oneshot::Receiver<io::Result<Reply>>> {
+        let (reply, result) = oneshot::channel();
+        self.sender
+            .send((command, reply))
+            .await
+            .map_err(|_| stopped())?;
+        Ok(result)
+    }
+}
@@ -0,0 +1,261 @@
+//! Settings from a TOML file, used as defaults of the command line options.
+//!
+//! Sections are the help headings of the options, with the options by their name
+//! in snake case. Options which can be repeated take an array:
+//!
+//! ```toml
+//! [video]
+//! width = 1280
+//! height = 720
+//! overlay = ["top-left:%F %T", "bottom-right:{camera}"]
+//!
+//! [recording]
+//! record_dir = "/var/lib/camera"
+//! record_triggered = true
+//! ```
+//!
+//! Options given on the command line override the file. A flag set in the file
+//! is turned off with `--no-` before its name, like `--no-record-triggered`.
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Arg, ArgAction, ArgMatches, Command, CommandFactory};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// Id of the option naming the configuration file
+const CONFIG_OPTION: &str = "config";
+
+/// Parses the command line, with defaults from the file of `--config` if given.
+/// Exits on invalid command line options like [clap::Parser::parse].
+pub fn parse<T: CommandFactory>() -> Result<ArgMatches> {
+    let matches = command::<T>().get_matches();
+    let Some(path) = matches.get_one::<PathBuf>(CONFIG_OPTION) else {
+        return Ok(matches);
+    };
+    Ok(with_file(command::<T>(), path, &matches)?.get_matches())
+}
+
+/// Reads the configuration file again, overridden by the same command line.
+pub fn reload<T: CommandFactory>(path: &Path) -> Result<ArgMatches> {
+    reload_from::<T>(path, std::env::args_os().collect())
+}
+
+/// [reload] with the command line `args`
+fn reload_from<T: CommandFactory>(path: &Path, args: Vec<OsString>) -> Result<ArgMatches> {
+    let matches = command::<T>().try_get_matches_from(&args)?;
+    Ok(with_file(command::<T>(), path, &matches)?.try_get_matches_from(&args)?)
+}
+
+/// The command of `T` with a hidden `--no-FLAG` for each flag, turning off one set in the file.
+fn command<T: CommandFactory>() -> Command {
+    let command = T::command();
+    let negations: Vec<Arg> = command
+        .get_arguments()
+        .filter(|arg| matches!(arg.get_action(), ArgAction::SetTrue))
+        .filter_map(|arg| {
+            let long = arg.get_long()?;
+            let negation = Arg::new(negation_id(arg.get_id().as_str()))
+                .long(format!("no-{long}"))
+                .action(ArgAction::SetTrue)
+                .conflicts_with(arg.get_id().clone())
+                .hide(true);
+            Some(negation)
+        })
+        .collect();
+    command.args(negations)
+}
+
+fn negation_id(id: &str) -> String {
+    format!("no_{id}")
+}
+
+/// Ids of the options with other values in `new` than in `old`.
+pub fn changes<T: CommandFactory>(old: &ArgMatches, new: &ArgMatches) -> Vec<String> {
+    let raw_values = |matches: &ArgMatches, id: &str| -> Vec<std::ffi::OsString> {
+        matches
+            .get_raw(id)
+            .into_iter()
+            .flatten()
+            .map(ToOwned::to_owned)
+            .collect()
+    };
+    T::command()
+        .get_arguments()
+        .map(|arg| arg.get_id().as_str().to_owned())
+        .filter(|id| raw_values(old, id) != raw_values(new, id))
+        .collect()
+}
+
+/// Sets the options of the file as defaults of `command`,
+/// except flags turned off in the command line `matches`.
+fn with_file(mut command: Command, path: &Path, matches: &ArgMatches) -> Result<Command> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let table = text
+        .parse::<toml::Table>()
+        .with_context(|| format!("invalid configuration {}", path.display()))?;
+    for (section, options) in table {
+        let toml::Value::Table(options) = options else {
+            bail!(
+                "{}: {section} is not a section like [video]",
+                path.display()
+            );
+        };
+        for (name, value) in options {
+            let values = option_values(&command, &section, &name, value)
+                .map_err(|e| anyhow!("{}: {section}.{name}: {e}", path.display()))?;
+            // not defined for other options than flags
+            if let Ok(Some(&true)) = matches.try_get_one::<bool>(&negation_id(&name)) {
+                continue;
+            }
+            command = command.mut_arg(name, |arg| arg.default_values(values));
+        }
+    }
+    Ok(command)
+}
+
+/// Converts a value of the file into values of the option, checked by its value parser.
+fn option_values(
+    command: &Command,
+    section: &str,
+    name: &str,
+    value: toml::Value,
+) -> Result<Vec<String>, String> {
+    let arg = command
+        .get_arguments()
+        .find(|arg| {
+            arg.get_id() == name
+                && arg.get_help_heading().map(section_name).as_deref() == Some(section)
+        })
+        .ok_or("no such option in this section")?;
+    let values = match value {
+        toml::Value::Array(values) if matches!(arg.get_action(), ArgAction::Append) => values,
+        toml::Value::Array(_) => return Err("expected a single value".to_owned()),
+        value => vec![value],
+    };
+    values
+        .into_iter()
+        .map(|value| {
+            let value = match value {
+                toml::Value::String(x) => x,
+                toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => {
+                    value.to_string()
+                }
+                _ => return Err(format!("unsupported value {value}")),
+            };
+            arg.get_value_parser()
+                .parse_ref(command, Some(arg), OsStr::new(&value))
+                .map_err(|e| {
+                    // the first line without the "error: " prefix, not the usage
+                    let message = e.to_string();
+                    let line = message.lines().next().unwrap_or_default();
+                    line.trim_start_matches("error: ").to_owned()
+                })?;
+            Ok(value)
+        })
+        .collect()
+}
+
+/// Section of the options of a help heading, like `remote_video` for "Remote video"
+fn section_name(heading: &str) -> String {
+    heading.to_lowercase().replace(' ', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{FromArgMatches, Parser};
+
+    #[derive(Parser)]
+    struct Cli {
+        #[clap(long)]
+        config: Option<PathBuf>,
+        #[clap(long, next_help_heading = "Video", default_value = "640")]
+        width: u32,
+        #[clap(long)]
+        overlay: Vec<String>,
+        #[clap(long)]
+        flip: bool,
+        #[clap(long, next_help_heading = "Remote video")]
+        remote_device: Option<String>,
+    }
+
+    /// Parses `args` with the configuration `toml`
+    fn load(name: &str, toml: &str, args: &[&str]) -> Result<ArgMatches> {
+        let path = std::env::temp_dir().join(format!("config-{}-{name}.toml", std::process::id()));
+        std::fs::write(&path, toml)?;
+        let args = std::iter::once("test").chain(args.iter().copied());
+        let matches = reload_from::<Cli>(&path, args.map(OsString::from).collect());
+        std::fs::remove_file(&path)?;
+        matches
+    }
+
+    fn error(name: &str, toml: &str) -> String {
+        format!("{:#}", load(name, toml, &[]).unwrap_err())
+    }
+
+    #[test]
+    fn sets_defaults_by_section() {
+        let toml = "[video]\nwidth = 1280\noverlay = [\"a\", \"b\"]\nflip = true\n\n\
+                    [remote_video]\nremote_device = \"/dev/video2\"\n";
+        let cli = Cli::from_arg_matches(&load("sections", toml, &[]).unwrap()).unwrap();
+        assert_eq!(cli.width, 1280);
+        assert_eq!(cli.overlay, ["a", "b"]);
+        assert!(cli.flip);
+        assert_eq!(cli.remote_device.as_deref(), Some("/dev/video2"));
+    }
+
+    #[test]
+    fn command_line_overrides_file() {
+        let toml = "[video]\nwidth = 1280\noverlay = [\"a\", \"b\"]\nflip = true\n";
+        let args = ["--width", "320", "--overlay", "c", "--no-flip"];
+        let cli = Cli::from_arg_matches(&load("precedence", toml, &args).unwrap()).unwrap();
+        assert_eq!(cli.width, 320);
+        assert_eq!(cli.overlay, ["c"]);
+        assert!(!cli.flip);
+    }
+
+    #[test]
+    fn rejects_unknown_section() {
+        let message = error("section", "[audio]\nwidth = 1280\n");
+        assert!(message.contains("audio.width: no such option"), "{message}");
+        let message = error("not-section", "width = 1280\n");
+        assert!(message.contains("width is not a section"), "{message}");
+    }
+
+    #[test]
+    fn rejects_unknown_key() {
+        let message = error("key", "[video]\nheight = 720\n");
+        assert!(
+            message.contains("video.height: no such option"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_type() {
+        let message = error("type", "[video]\nwidth = \"wide\"\n");
+        assert!(message.contains("video.width: invalid value"), "{message}");
+        let message = error("table", "[video]\nwidth = { pixels = 1280 }\n");
+        assert!(
+            message.contains("video.width: unsupported value"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn rejects_array_of_single_value() {
+        let message = error("array", "[video]\nwidth = [1280, 720]\n");
+        assert!(
+            message.contains("video.width: expected a single value"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn lists_changes() {
+        let old = load("old", "[video]\nwidth = 1280\n", &[]).unwrap();
+        let new = load("new", "[video]\nwidth = 1280\noverlay = [\"a\"]\n", &[]).unwrap();
+        assert_eq!(changes::<Cli>(&old, &new), ["overlay"]);
+    }
+}
@@ -0,0 +1,111 @@
+//! HTTP API to control the running stream.
+//!
+//! - `POST /trigger` records an event when recording is triggered.
+//! - `GET /snapshot` returns a JPEG of the camera, with privacy masks and overlay.
+//! - `GET /signaling` is the WebSocket of [crate::signaling], if signaling is enabled.
+
+use crate::recorder::RecorderHandle;
+use crate::signaling::{SignalingHandle, SignalingMessage};
+use crate::snapshot::SnapshotHandle;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use std::io;
+use std::net::SocketAddr;
+use tokio::sync::broadcast::error::RecvError;
+
+#[derive(Clone)]
+pub struct ControlState {
+    pub recorder: Option<RecorderHandle>,
+    pub snapshot: SnapshotHandle,
+    pub signaling: Option<SignalingHandle>,
+}
+
+/// Serves the API on `address` until an error occurs.
+pub async fn serve(address: SocketAddr, state: ControlState) -> io::Result<()> {
+    let app = Router::new()
+        .route("/trigger", post(trigger))
+        .route("/snapshot", get(snapshot))
+        .route("/signaling", get(signaling))
+        .with_state(state);
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    println!("control server listening on {address}");
+    axum::serve(listener, app).await
+}
+
+async fn trigger(State(state): State<ControlState>) -> StatusCode {
+    match &state.recorder {
+        Some(recorder) => {
+            recorder.trigger("HTTP request");
+            StatusCode::ACCEPTED
+        }
+        None => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+async fn snapshot(State(state): State<ControlState>) -> Response {
+    match state.snapshot.take().await {
+        Ok(jpeg) => ([(header::CONTENT_TYPE, "image/jpeg")], jpeg).into_response(),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, e.to_string()).into_response(),
+    }
+}
+
+async fn signaling(State(state): State<ControlState>, upgrade: WebSocketUpgrade) -> Response {
+    match state.signaling {
+        Some(signaling) => upgrade.on_upgrade(move |socket| serve_signaling(socket, signaling)),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Passes messages between the WebSocket and the session until either ends.
+async fn serve_signaling(mut socket: WebSocket, signaling: SignalingHandle) {
+    let (history, mut sent) = signaling.subscribe();
+    for message in history {
+        let Ok(json) = serde_json::to_string(&message) else {
+            continue;
+        };
+        if socket.send(Message::Text(json)).await.is_err() {
+            return;
+        }
+    }
+    loop {
+        tokio::select! {
+            message = socket.recv() => {
+                let Some(Ok(message)) = message else {
+                    break;
+                };
+                // pings are answered by axum
+                let Message::Text(text) = message else {
+                    continue;
+                };
+                match serde_json::from_str::<SignalingMessage>(&text) {
+                    Ok(message) => {
+                        if !signaling.receive(message).await {
+                            break;
+                        }
+                    }
+                    Err(e) => println!("invalid signaling message: {e}"),
+                }
+            }
+            message = sent.recv() => {
+                let message = match message {
+                    Ok(message) => message,
+                    Err(RecvError::Lagged(count)) => {
+                        println!("dropped {count} signaling messages");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let Ok(json) = serde_json::to_string(&message) else {
+                    continue;
+                };
+                if socket.send(Message::Text(json)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
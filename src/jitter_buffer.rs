@@ -0,0 +1,379 @@
+use crate::opus_decoder::has_fec;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Reorders incoming RTP packets and tells the playback what to do for each frame.
+///
+/// Packets are keyed on the extended (unwrapped) RTP sequence number.
+/// The playout delay follows the interarrival jitter estimated as in RFC 3550 section 6.4.1.
+pub struct JitterBuffer {
+    clock_rate: u32,
+    min_depth: usize,
+    max_depth: usize,
+    packets: BTreeMap<u64, BufferedPacket>,
+    /// the highest extended sequence number we have seen, used to unwrap new ones
+    highest_sequence: Option<u64>,
+    /// the extended sequence number played next, or None while (re-)buffering
+    next_sequence: Option<u64>,
+    /// the timestamp of the frame played last
+    last_timestamp: Option<u32>,
+    /// samples per packet found from timestamps, used to conceal lost packets
+    frame_samples: u32,
+    /// estimated jitter in timestamp units
+    jitter: f64,
+    last_transit: Option<i64>,
+    /// arrival of the first packet
+    epoch: Option<Instant>,
+    /// the timestamp of the first packet
+    first_timestamp: Option<u32>,
+    concealed_in_row: usize,
+    stats: JitterBufferStats,
+}
+
+struct BufferedPacket {
+    timestamp: u32,
+    payload: Vec<u8>,
+}
+
+/// What the playback should do for the next frame.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Playout {
+    /// Decode this packet as usual.
+    Packet(Vec<u8>),
+    /// The packet is lost but the next one is here with in-band FEC data.
+    /// Decode `samples` samples from the FEC data of the next packet.
+    Fec { next: Vec<u8>, samples: u32 },
+    /// The packet is lost. Generate `samples` samples with the decoder's PLC.
+    Conceal { samples: u32 },
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JitterBufferStats {
+    pub received: u64,
+    /// packets arrived after they were due, or duplicated
+    pub late: u64,
+    pub recovered_by_fec: u64,
+    pub concealed: u64,
+    /// packets dropped to shrink the buffer after a jitter spike
+    pub dropped: u64,
+}
+
+/// Stop concealing after this many frames in a row and wait for the buffer to fill again.
+/// This happens when the remote peer stops sending, e.g. with DTX.
+const MAX_CONCEAL_IN_ROW: usize = 5;
+
+impl JitterBuffer {
+    pub fn new(clock_rate: u32, min_depth: usize, max_depth: usize) -> Self {
+        Self {
+            clock_rate,
+            min_depth,
+            max_depth,
+            packets: BTreeMap::new(),
+            highest_sequence: None,
+            next_sequence: None,
+            last_timestamp: None,
+            // 20 ms until we know the actual packet duration
+            frame_samples: clock_rate / 50,
+            jitter: 0.0,
+            last_transit: None,
+            epoch: None,
+            first_timestamp: None,
+            concealed_in_row: 0,
+            stats: JitterBufferStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> JitterBufferStats {
+        self.stats
+    }
+
+    /// The estimated interarrival jitter.
+    pub fn jitter(&self) -> Duration {
+        Duration::from_secs_f64(self.jitter / self.clock_rate as f64)
+    }
+
+    /// The timestamp and arrival of the first packet, where the timeline of the track starts.
+    pub fn origin(&self) -> Option<(u32, Instant)> {
+        self.first_timestamp.zip(self.epoch)
+    }
+
+    /// The timestamp of the frame taken last with [Self::pop], including lost frames.
+    pub fn last_timestamp(&self) -> Option<u32> {
+        self.last_timestamp
+    }
+
+    /// The number of packets we want to keep buffered for the current jitter.
+    pub fn target_depth(&self) -> usize {
+        // keep twice of the jitter plus one packet which is being played
+        let jitter_packets = (self.jitter * 2.0 / self.frame_samples as f64).ceil() as usize;
+        (jitter_packets + 1).clamp(self.min_depth, self.max_depth)
+    }
+
+    pub fn push(&mut self, sequence: u16, timestamp: u32, payload: Vec<u8>, arrival: Instant) {
+        self.stats.received += 1;
+        self.first_timestamp.get_or_insert(timestamp);
+        self.update_jitter(timestamp, arrival);
+
+        let sequence = self.unwrap_sequence(sequence);
+        if self.next_sequence.is_some_and(|next| sequence < next)
+            || self.packets.contains_key(&sequence)
+        {
+            self.stats.late += 1;
+            return;
+        }
+        self.packets
+            .insert(sequence, BufferedPacket { timestamp, payload });
+    }
+
+    /// Takes what to play for the next frame.
+    /// `None` means we're buffering and the playback should output nothing.
+    pub fn pop(&mut self) -> Option<Playout> {
+        let mut next = match self.next_sequence {
+            Some(next) => next,
+            None => {
+                if self.packets.len() < self.target_depth() {
+                    return None;
+                }
+                *self.packets.keys().next().unwrap()
+            }
+        };
+
+        // after a jitter spike, the buffer holds more than needed so drop the oldest
+        while self.packets.len() > self.target_depth() + 2 {
+            let (&sequence, packet) = self.packets.iter().next().unwrap();
+            self.last_timestamp = Some(packet.timestamp);
+            self.packets.remove(&sequence);
+            self.stats.dropped += 1;
+            next = sequence + 1;
+        }
+
+        if let Some(packet) = self.packets.remove(&next) {
+            self.update_frame_samples(packet.timestamp);
+            self.next_sequence = Some(next + 1);
+            self.concealed_in_row = 0;
+            return Some(Playout::Packet(packet.payload));
+        }
+
+        if (self.packets.is_empty() && self.concealed_in_row >= MAX_CONCEAL_IN_ROW)
+            || self.next_sequence.is_none()
+        {
+            self.next_sequence = None;
+            return None;
+        }
+
+        self.next_sequence = Some(next + 1);
+        self.concealed_in_row += 1;
+        let samples = self.frame_samples;
+        self.last_timestamp = self.last_timestamp.map(|x| x.wrapping_add(samples));
+        match self.packets.get(&(next + 1)) {
+            Some(following) if has_fec(&following.payload) => {
+                self.stats.recovered_by_fec += 1;
+                Some(Playout::Fec {
+                    next: following.payload.clone(),
+                    samples,
+                })
+            }
+            _ => {
+                self.stats.concealed += 1;
+                Some(Playout::Conceal { samples })
+            }
+        }
+    }
+
+    fn update_frame_samples(&mut self, timestamp: u32) {
+        if let Some(last) = self.last_timestamp {
+            let diff = timestamp.wrapping_sub(last);
+            // ignore a jump after DTX silence or a timestamp reset
+            if diff != 0 && diff <= self.clock_rate / 8 {
+                self.frame_samples = diff;
+            }
+        }
+        self.last_timestamp = Some(timestamp);
+    }
+
+    fn unwrap_sequence(&mut self, sequence: u16) -> u64 {
+        let extended = match self.highest_sequence {
+            None => sequence as u64 + (1 << 16),
+            Some(highest) => {
+                // choose the nearest one to the highest sequence number
+                let diff = sequence.wrapping_sub(highest as u16) as i16 as i64;
+                (highest as i64 + diff) as u64
+            }
+        };
+        if self.highest_sequence < Some(extended) {
+            self.highest_sequence = Some(extended);
+        }
+        extended
+    }
+
+    fn update_jitter(&mut self, timestamp: u32, arrival: Instant) {
+        let epoch = *self.epoch.get_or_insert(arrival);
+        let arrival = (arrival.duration_since(epoch).as_secs_f64() * self.clock_rate as f64) as i64;
+        let transit = arrival - timestamp as i64;
+        if let Some(last_transit) = self.last_transit {
+            let mut d = (transit - last_transit).abs();
+            // the timestamp wrapped around
+            if d > (1 << 31) {
+                d = (d - (1 << 32)).abs();
+            }
+            self.jitter += (d as f64 - self.jitter) / 16.0;
+        }
+        self.last_transit = Some(transit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLOCK_RATE: u32 = 48000;
+    /// 20 ms
+    const FRAME_SAMPLES: u32 = 960;
+
+    /// A SILK-only 20 ms packet told apart by `id`, with in-band FEC data if `fec`
+    fn payload(id: u8, fec: bool) -> Vec<u8> {
+        vec![0x08, if fec { 0x40 } else { 0x00 }, id]
+    }
+
+    /// Pushes packets arriving on time for their timestamps.
+    fn push(buffer: &mut JitterBuffer, start: Instant, packets: &[(u16, u8, bool)]) {
+        for &(sequence, id, fec) in packets {
+            let timestamp = id as u32 * FRAME_SAMPLES;
+            let arrival = start + Duration::from_millis(id as u64 * 20);
+            buffer.push(sequence, timestamp, payload(id, fec), arrival);
+        }
+    }
+
+    fn packet(id: u8) -> Option<Playout> {
+        Some(Playout::Packet(payload(id, false)))
+    }
+
+    #[test]
+    fn buffers_until_the_target_depth() {
+        let mut buffer = JitterBuffer::new(CLOCK_RATE, 3, 3);
+        let start = Instant::now();
+        push(&mut buffer, start, &[(1, 1, false), (2, 2, false)]);
+        assert_eq!(buffer.pop(), None);
+        push(&mut buffer, start, &[(3, 3, false)]);
+        assert_eq!(buffer.pop(), packet(1));
+    }
+
+    #[test]
+    fn reorders_packets() {
+        let mut buffer = JitterBuffer::new(CLOCK_RATE, 3, 3);
+        push(
+            &mut buffer,
+            Instant::now(),
+            &[(1, 1, false), (3, 3, false), (2, 2, false)],
+        );
+        assert_eq!(buffer.pop(), packet(1));
+        assert_eq!(buffer.pop(), packet(2));
+        assert_eq!(buffer.pop(), packet(3));
+    }
+
+    #[test]
+    fn conceals_a_lost_packet() {
+        let mut buffer = JitterBuffer::new(CLOCK_RATE, 3, 3);
+        push(
+            &mut buffer,
+            Instant::now(),
+            &[(1, 1, false), (2, 2, false), (4, 4, false)],
+        );
+        assert_eq!(buffer.pop(), packet(1));
+        assert_eq!(buffer.pop(), packet(2));
+        assert_eq!(
+            buffer.pop(),
+            Some(Playout::Conceal {
+                samples: FRAME_SAMPLES
+            })
+        );
+        assert_eq!(buffer.pop(), packet(4));
+        assert_eq!(buffer.stats().concealed, 1);
+        assert_eq!(buffer.stats().recovered_by_fec, 0);
+    }
+
+    #[test]
+    fn recovers_a_lost_packet_with_fec_of_the_next_one() {
+        let mut buffer = JitterBuffer::new(CLOCK_RATE, 3, 3);
+        push(
+            &mut buffer,
+            Instant::now(),
+            &[(1, 1, false), (2, 2, false), (4, 4, true)],
+        );
+        assert_eq!(buffer.pop(), packet(1));
+        assert_eq!(buffer.pop(), packet(2));
+        assert_eq!(
+            buffer.pop(),
+            Some(Playout::Fec {
+                next: payload(4, true),
+                samples: FRAME_SAMPLES
+            })
+        );
+        assert_eq!(buffer.pop(), Some(Playout::Packet(payload(4, true))));
+        assert_eq!(buffer.stats().recovered_by_fec, 1);
+        assert_eq!(buffer.stats().concealed, 0);
+    }
+
+    #[test]
+    fn drops_late_and_duplicate_packets() {
+        let mut buffer = JitterBuffer::new(CLOCK_RATE, 1, 1);
+        let start = Instant::now();
+        push(&mut buffer, start, &[(1, 1, false)]);
+        assert_eq!(buffer.pop(), packet(1));
+        push(
+            &mut buffer,
+            start,
+            &[(1, 1, false), (2, 2, false), (2, 2, false)],
+        );
+        assert_eq!(buffer.stats().late, 2);
+        assert_eq!(buffer.pop(), packet(2));
+        assert_eq!(buffer.stats().received, 4);
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let mut buffer = JitterBuffer::new(CLOCK_RATE, 3, 3);
+        push(
+            &mut buffer,
+            Instant::now(),
+            &[(65535, 2, false), (0, 3, false), (65534, 1, false)],
+        );
+        assert_eq!(buffer.pop(), packet(1));
+        assert_eq!(buffer.pop(), packet(2));
+        assert_eq!(buffer.pop(), packet(3));
+    }
+
+    #[test]
+    fn stops_concealing_and_buffers_again() {
+        let mut buffer = JitterBuffer::new(CLOCK_RATE, 1, 1);
+        push(&mut buffer, Instant::now(), &[(1, 1, false)]);
+        assert_eq!(buffer.pop(), packet(1));
+        for _ in 0..MAX_CONCEAL_IN_ROW {
+            assert!(matches!(buffer.pop(), Some(Playout::Conceal { .. })));
+        }
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.stats().concealed, MAX_CONCEAL_IN_ROW as u64);
+    }
+
+    #[test]
+    fn target_depth_follows_the_jitter() {
+        let start = Instant::now();
+        let mut steady = JitterBuffer::new(CLOCK_RATE, 1, 10);
+        let mut jittery = JitterBuffer::new(CLOCK_RATE, 1, 10);
+        let mut clamped = JitterBuffer::new(CLOCK_RATE, 1, 2);
+        for i in 0..50u32 {
+            let timestamp = i * FRAME_SAMPLES;
+            let on_time = start + Duration::from_millis(i as u64 * 20);
+            // every other packet is 40 ms late
+            let late = on_time + Duration::from_millis(i as u64 % 2 * 40);
+            steady.push(i as u16, timestamp, payload(0, false), on_time);
+            jittery.push(i as u16, timestamp, payload(0, false), late);
+            clamped.push(i as u16, timestamp, payload(0, false), late);
+        }
+        // rounding of arrival times may look like a little jitter
+        assert!(steady.target_depth() <= 2);
+        assert!(jittery.target_depth() >= 4, "{}", jittery.target_depth());
+        assert!(jittery.target_depth() <= 10);
+        assert_eq!(clamped.target_depth(), 2);
+    }
+}
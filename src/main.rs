@@ -8,43 +8,115 @@
 
 // You can use https://jsfiddle.net/qnt9sx0p/ as browser side
 
+mod async_pcm;
 mod audio;
+mod audio_capture;
+mod audio_file;
+mod audio_io;
+mod audio_mixer;
+mod audio_playback;
+mod audio_processing;
 mod camera_capture;
-mod monaural_audio_capture;
-mod monaural_audio_playback;
+mod camera_control;
+mod config;
+mod control_server;
+mod jitter_buffer;
+mod matroska;
+mod motion_detection;
 mod nal_parser;
-
+mod opus_decoder;
+mod opus_encoder;
+mod overlay;
+mod privacy_mask;
+mod recorder;
+mod resampler;
+mod resolution_adaptation;
+mod scaler;
+mod signaling;
+mod snapshot;
+mod video_output;
+mod voice_activity;
+
+use crate::audio::{FrameDuration, XrunPolicy, OPUS_SAMPLE_RATE};
+use crate::audio_capture::AudioCapture;
+use crate::audio_file::{is_ogg_opus, OggOpusSink, OggOpusSource, WavSink, WavSource};
+use crate::audio_io::{AudioSink, AudioSource};
+use crate::audio_mixer::{AudioMixer, MixerInputHandle};
+use crate::audio_playback::AudioPlayback;
+use crate::audio_processing::{AudioProcessingConfig, AudioProcessor, NoiseSuppressionLevel};
 use crate::camera_capture::CameraCapture;
-use crate::monaural_audio_capture::MonauralAudioCapture;
-use crate::monaural_audio_playback::MonauralAudioPlayback;
+use crate::camera_control::{
+    CameraControlHandle, Command, Event, Reply, Request, Response, CONTROL_CHANNEL_ID,
+    CONTROL_CHANNEL_LABEL,
+};
+use crate::control_server::ControlState;
+use crate::jitter_buffer::{JitterBuffer, Playout};
+use crate::motion_detection::{MotionConfig, MotionDetector, MotionEvent, Zone};
 use crate::nal_parser::H264Parser;
+use crate::opus_encoder::{OpusApplication, OpusBandwidth, OpusEncoderConfig};
+use crate::overlay::{Overlay, OverlayShape, OverlayText};
+use crate::recorder::{RecorderConfig, RecorderHandle, RecordingMode};
+use crate::resolution_adaptation::ResolutionAdapter;
+use crate::signaling::{SignalingHandle, SignalingMessage};
+use crate::snapshot::SnapshotHandle;
+use crate::video_output::{AnnexBFile, V4l2Decoder, V4l2Output, VideoSink};
+use crate::voice_activity::VoiceActivityEvent;
 use anyhow::Result;
-use clap::Parser;
+use clap::{ArgMatches, FromArgMatches};
+use serde::Serialize;
 use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::Notify;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, oneshot, Notify};
 use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264, MIME_TYPE_OPUS};
+use webrtc::api::setting_engine::SettingEngine;
 use webrtc::api::APIBuilder;
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::ice::network_type::NetworkType;
+use webrtc::ice::udp_network::{EphemeralUDP, UDPNetwork};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
+use webrtc::ice_transport::ice_candidate_type::RTCIceCandidateType;
 use webrtc::ice_transport::ice_connection_state::RTCIceConnectionState;
 use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::interceptor::registry::Registry;
+use webrtc::media::io::sample_builder::SampleBuilder;
 use webrtc::media::Sample;
 use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtcp::receiver_report::ReceiverReport;
+use webrtc::rtp::codecs::h264::H264Packet;
+use webrtc::rtp::extension::audio_level_extension::AudioLevelExtension;
+use webrtc::rtp::extension::HeaderExtension;
 use webrtc::rtp_transceiver::rtp_codec::{
-    RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType,
+    RTCRtpCodecCapability, RTCRtpCodecParameters, RTCRtpHeaderExtensionCapability, RTPCodecType,
 };
+use webrtc::sdp::extmap::{AUDIO_LEVEL_URI, SDES_MID_URI, SDES_RTP_STREAM_ID_URI};
 use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
 use webrtc::track::track_local::TrackLocal;
+use webrtc::track::track_remote::TrackRemote;
 
 #[derive(clap::Parser)]
 struct Cli {
+    /// TOML file with defaults of the options below, in sections by heading like [video].
+    /// Flags set in it are turned off by --no-FLAG, like --no-record-triggered.
+    /// Read again on SIGHUP to apply changes of bitrate, overlay and privacy masks
+    #[clap(long)]
+    config: Option<PathBuf>,
+
     /// The capture device to be streamed
-    #[clap(long, default_value = "0")]
+    #[clap(long, default_value = "0", next_help_heading = "Video")]
     camera_device: usize,
     /// The hw encoder device
     #[clap(long, default_value = "11")]
@@ -67,28 +139,242 @@ struct Cli {
     /// FourCC to use capture & input format of encoder
     #[clap(long, default_value = "YUYV")]
     camera_fourcc: FourCC,
+    /// Capture width if other than the streaming width, scaled for the encoder
+    #[clap(long)]
+    capture_width: Option<u32>,
+    /// Capture height if other than the streaming height, scaled for the encoder
+    #[clap(long)]
+    capture_height: Option<u32>,
+    /// FourCC the encoder takes if other than the camera's, converted by the scaler device
+    #[clap(long)]
+    stream_fourcc: Option<FourCC>,
+    /// V4L2 M2M device number to scale captured frames with (e.g. the ISP of a Pi),
+    /// scaled in software if not given or not available
+    #[clap(long)]
+    scaler_device: Option<usize>,
+    /// Text drawn into the video and snapshots as POSITION:TEXT. POSITION is ANCHOR[@X,Y],
+    /// where ANCHOR is top-left, top, top-right, left, center, right, bottom-left, bottom
+    /// or bottom-right, moved by X,Y pixels like bottom-right@-16,-8. TEXT is a strftime
+    /// format of the local time like "%F %T", and {camera} is the name of the camera.
+    /// Can be repeated
+    #[clap(long, value_parser = OverlayText::parse)]
+    overlay: Vec<OverlayText>,
+    /// Shape drawn into the video and snapshots as rectangle:X,Y,WIDTH,HEIGHT
+    /// or line:X0,Y0,X1,Y1 in fractions of the picture. Can be repeated
+    #[clap(long, value_parser = OverlayShape::parse)]
+    overlay_shape: Vec<OverlayShape>,
+    /// Size of overlay text in pixels per font pixel, and thickness of shapes
+    #[clap(long, default_value = "2")]
+    overlay_scale: u32,
+    /// Draw overlay text on a black box
+    #[clap(long)]
+    overlay_background: bool,
+    /// JSON file of privacy masks, blacking out or pixelating regions of the video.
+    /// Masks changed over the control data channel are saved to it
+    #[clap(long)]
+    privacy_masks: Option<PathBuf>,
+    /// Target bitrate of video (bit per second), the encoder's default if not given
+    #[clap(long)]
+    video_bitrate: Option<u32>,
+    /// Lower the resolution while the viewer reports packet loss
+    #[clap(long)]
+    adaptive_resolution: bool,
+    /// Number of simulcast layers, each half the size of the previous one.
+    /// The encoder device is opened once per layer
+    #[clap(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..=3))]
+    simulcast_layers: u32,
+
+    // motion detection options
+    /// Detect motion in the camera picture (YUYV, NV12, YU12 or GREY only)
+    #[clap(long, next_help_heading = "Motion detection")]
+    motion_detection: bool,
+    /// Zone to watch for motion as X,Y,WIDTH,HEIGHT in fractions of the picture.
+    /// Can be repeated, the whole picture if not given
+    #[clap(long, value_parser = parse_zone)]
+    motion_zone: Vec<Zone>,
+    /// Sensitivity to luma changes (1-100)
+    #[clap(long, default_value = "90", value_parser = clap::value_parser!(u8).range(1..=100))]
+    motion_sensitivity: u8,
+    /// Percentage of a zone which has to change to be motion
+    #[clap(long, default_value = "1.0")]
+    motion_min_area: f32,
+    /// Trigger recording while motion is detected
+    #[clap(long)]
+    motion_trigger: bool,
+    /// Force a keyframe when motion starts
+    #[clap(long)]
+    motion_keyframe: bool,
 
     // audio options
-    /// Sampling rate of capture (Hz)
-    #[clap(long, default_value = "48000")]
+    /// Sampling rate of capture device (Hz), resampled to 48 kHz for Opus
+    #[clap(long, default_value = "48000", next_help_heading = "Audio")]
     sample_rate: u32,
+    /// Number of capture channels (1 for mono, 2 for stereo)
+    #[clap(long, default_value = "1")]
+    channels: u32,
     /// Bitrate of audio (bit per second)
     #[clap(long, default_value = "28000")]
     bit_rate: u32,
-    /// Length of audio capture frame (milliseconds)
+    /// Opus coding mode
+    #[clap(long, value_enum, default_value = "voip")]
+    opus_application: OpusApplication,
+    /// Encode audio with constant bitrate instead of variable bitrate
+    #[clap(long)]
+    cbr: bool,
+    /// Allow variable bitrate to exceed the target bitrate
+    #[clap(long)]
+    unconstrained_vbr: bool,
+    /// Add in-band forward error correction to audio
+    #[clap(long)]
+    fec: bool,
+    /// Expected packet loss (percent), more redundancy is added by FEC for higher values
+    #[clap(long, default_value = "0", value_parser = clap::value_parser!(u8).range(0..=100))]
+    packet_loss: u8,
+    /// Stop sending audio while silent (discontinuous transmission)
+    #[clap(long)]
+    dtx: bool,
+    /// Opus encoder complexity (0-10)
+    #[clap(long, default_value = "10", value_parser = clap::value_parser!(u8).range(0..=10))]
+    complexity: u8,
+    /// Maximum audio bandwidth of Opus encoder
+    #[clap(long, value_enum)]
+    max_bandwidth: Option<OpusBandwidth>,
+    /// Length of audio capture frame (milliseconds).
+    /// One of 2.5, 5, 10, 20, 40, 60, or 80, 100, 120 for packets of multiple frames
     #[clap(long, default_value = "20")]
-    frame_ms: u32,
+    frame_ms: FrameDuration,
     /// Audio Device Name
     #[clap(long, default_value = "plughw:1,0")]
     audio_device: String,
+    /// Send audio from a file instead of the audio device.
+    /// Ogg Opus (.ogg, .opus) is sent as is, 16-bit WAV is encoded. Loops at the end.
+    #[clap(long)]
+    audio_file: Option<PathBuf>,
+    /// Cancel echo of the speaker in captured audio
+    #[clap(long)]
+    echo_cancellation: bool,
+    /// Suppress noise in captured audio with the level
+    #[clap(long, value_enum)]
+    noise_suppression: Option<NoiseSuppressionLevel>,
+    /// Adjust the level of captured audio automatically
+    #[clap(long)]
+    auto_gain_control: bool,
+    /// What to do with audio interrupted by overrun or underrun of capture and speaker
+    #[clap(long, value_enum, default_value = "conceal")]
+    xrun_policy: XrunPolicy,
+
+    // recording options
+    /// Record sent video and audio into Matroska files in this directory
+    #[clap(long, next_help_heading = "Recording")]
+    record_dir: Option<PathBuf>,
+    /// Start a new recording file at the next keyframe after this size (MB)
+    #[clap(long, default_value = "512")]
+    record_max_size: u64,
+    /// Start a new recording file at the next keyframe after this duration (seconds)
+    #[clap(long, default_value = "600")]
+    record_max_duration: u64,
+    /// Also record the first received audio track
+    #[clap(long)]
+    record_remote: bool,
+    /// Record only around triggered events instead of everything
+    #[clap(long)]
+    record_triggered: bool,
+    /// Media kept in memory to be recorded before a triggered event (seconds)
+    #[clap(long, default_value = "10")]
+    pre_event: u64,
+    /// Keep recording a triggered event for this long after the last trigger (seconds)
+    #[clap(long, default_value = "30")]
+    post_event: u64,
+
+    // control options
+    /// Serve the HTTP control API on this address, e.g. 0.0.0.0:8080
+    #[clap(long, next_help_heading = "Control")]
+    http_listen: Option<SocketAddr>,
+    /// Take the offer from the WebSocket /signaling of --http-listen instead of stdin,
+    /// exchanging candidates as they are gathered (trickle ICE)
+    #[clap(long, requires = "http_listen")]
+    signaling: bool,
+
+    // ICE options
+    /// STUN or TURN server as URL[,USERNAME,CREDENTIAL] like stun:stun.l.google.com:19302
+    /// or turn:turn.example.com:3478,user,secret.
+    /// Can be repeated, replacing the default server
+    #[clap(
+        long,
+        next_help_heading = "ICE",
+        value_parser = parse_ice_server,
+        default_value = "stun:stun.l.google.com:19302"
+    )]
+    ice_server: Vec<IceServer>,
+    /// Username of the TURN servers given without one
+    #[clap(long)]
+    ice_username: Option<String>,
+    /// Credential of the TURN servers given without one
+    #[clap(long)]
+    ice_credential: Option<String>,
+    /// Candidates to use, relay for TURN only, which needs a TURN server
+    #[clap(long, value_enum, default_value = "all")]
+    ice_transport_policy: IceTransportPolicy,
+    /// Network interface to gather candidates on. Can be repeated, all if not given
+    #[clap(long)]
+    ice_interface: Vec<String>,
+    /// IP family to gather candidates of. Can be repeated, both if not given
+    #[clap(long, value_enum)]
+    ice_network: Vec<IceNetwork>,
+    /// Range of local UDP ports as MIN-MAX, any port if not given
+    #[clap(long, value_parser = parse_port_range)]
+    ice_udp_ports: Option<(u16, u16)>,
+    /// Public IP of this host behind a 1:1 NAT, replacing the IP of local candidates.
+    /// Can be repeated for each IP family
+    #[clap(long)]
+    nat_1to1_ip: Vec<String>,
 
     // speaker options
-    /// Speaker Sampling rate of capture (Hz)
-    #[clap(long, default_value = "48000")]
+    /// Sampling rate of speaker device (Hz), resampled from 48 kHz of Opus
+    #[clap(long, default_value = "48000", next_help_heading = "Speaker")]
     speaker_sample_rate: u32,
+    /// Number of speaker channels (1 for mono, 2 for stereo)
+    #[clap(long, default_value = "1")]
+    speaker_channels: u32,
     /// Speaker Audio Device Name
     #[clap(long, default_value = "default")]
     speaker_audio_device: String,
+    /// Write received audio to a file instead of the speaker device.
+    /// Ogg Opus (.ogg, .opus) stores packets of the first track as is,
+    /// otherwise tracks are mixed into 16-bit WAV at 48 kHz
+    #[clap(long)]
+    speaker_file: Option<PathBuf>,
+    // remote video options
+    /// Write received H.264 video to a V4L2 output device (e.g. v4l2loopback),
+    /// as is or decoded with --remote-video-decoder
+    #[clap(long, next_help_heading = "Remote video")]
+    remote_video_device: Option<usize>,
+    /// V4L2 M2M decoder device to decode received H.264 video for --remote-video-device
+    #[clap(long)]
+    remote_video_decoder: Option<usize>,
+    /// FourCC of decoded video written to --remote-video-device
+    #[clap(long, default_value = "YUYV")]
+    remote_video_fourcc: FourCC,
+    /// Width of received video, for the formats of the decoder and the output device
+    #[clap(long, default_value = "640")]
+    remote_video_width: u32,
+    /// Height of received video, for the formats of the decoder and the output device
+    #[clap(long, default_value = "480")]
+    remote_video_height: u32,
+    /// Write received H.264 video to an Annex-B file instead of --remote-video-device
+    #[clap(long)]
+    remote_video_file: Option<PathBuf>,
+
+    /// Gain of a received audio track when mixed, as TRACK_ID=GAIN.
+    /// Can be repeated, tracks not listed are mixed with gain 1.0
+    #[clap(long, value_parser = parse_track_gain, next_help_heading = "Received audio")]
+    track_gain: Vec<(String, f32)>,
+    /// Minimum number of packets held in jitter buffer of received audio
+    #[clap(long, default_value = "2")]
+    jitter_buffer_min: usize,
+    /// Maximum number of packets held in jitter buffer of received audio
+    #[clap(long, default_value = "15")]
+    jitter_buffer_max: usize,
 }
 
 #[derive(Copy, Clone)]
@@ -102,14 +388,183 @@ impl clap::builder::ValueParserFactory for FourCC {
     }
 }
 
+#[derive(Copy, Clone, clap::ValueEnum)]
+enum IceTransportPolicy {
+    All,
+    Relay,
+}
+
+#[derive(Clone)]
+struct IceServer {
+    url: String,
+    /// username and credential
+    credentials: Option<(String, String)>,
+}
+
+impl IceServer {
+    fn is_turn(&self) -> bool {
+        self.url.starts_with("turn:") || self.url.starts_with("turns:")
+    }
+}
+
+#[derive(Copy, Clone, clap::ValueEnum)]
+enum IceNetwork {
+    Ipv4,
+    Ipv6,
+}
+
+/// RIDs of simulcast layers: full, half and quarter size
+const SIMULCAST_RIDS: [&str; 3] = ["f", "h", "q"];
+/// How long a reload waits for the camera to take its changes.
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn parse_track_gain(value: &str) -> Result<(String, f32), String> {
+    let (id, gain) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected TRACK_ID=GAIN: {value}"))?;
+    let gain = gain.parse::<f32>().map_err(|e| e.to_string())?;
+    Ok((id.to_owned(), gain))
+}
+
+/// Sends a message on the control data channel, dropped while the channel isn't open.
+async fn send_json(data_channel: &RTCDataChannel, message: &impl Serialize) {
+    if data_channel.ready_state() != RTCDataChannelState::Open {
+        return;
+    }
+    let Ok(json) = serde_json::to_string(message) else {
+        return;
+    };
+    if let Err(e) = data_channel.send_text(json).await {
+        println!("failed to send on control data channel: {e}");
+    }
+}
+
+fn parse_ice_server(value: &str) -> Result<IceServer, String> {
+    // the credential may contain commas
+    let mut parts = value.splitn(3, ',');
+    let url = parts.next().unwrap_or_default().to_owned();
+    let credentials = match (parts.next(), parts.next()) {
+        (None, _) => None,
+        (Some(username), Some(credential)) => Some((username.to_owned(), credential.to_owned())),
+        (Some(_), None) => return Err(format!("expected URL,USERNAME,CREDENTIAL: {value}")),
+    };
+    let server = IceServer { url, credentials };
+    if server.credentials.is_some() && !server.is_turn() {
+        return Err(format!("only TURN servers take credentials: {value}"));
+    }
+    Ok(server)
+}
+
+fn parse_port_range(value: &str) -> Result<(u16, u16), String> {
+    let (min, max) = value
+        .split_once('-')
+        .ok_or_else(|| format!("expected MIN-MAX: {value}"))?;
+    let min = min.parse::<u16>().map_err(|e| e.to_string())?;
+    let max = max.parse::<u16>().map_err(|e| e.to_string())?;
+    if min > max {
+        return Err(format!("{min} is larger than {max}"));
+    }
+    Ok((min, max))
+}
+
+fn parse_zone(value: &str) -> Result<Zone, String> {
+    let values = value
+        .split(',')
+        .map(|x| x.trim().parse::<f32>().map_err(|e| e.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let [x, y, width, height] = values[..] else {
+        return Err(format!("expected X,Y,WIDTH,HEIGHT: {value}"));
+    };
+    Ok(Zone {
+        x,
+        y,
+        width,
+        height,
+    })
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let parsed = Cli::parse();
+    let matches = config::parse::<Cli>()?;
+    let parsed = Cli::from_arg_matches(&matches)?;
+    // clap doesn't check requirements of options set by the configuration file
+    if parsed.signaling && parsed.http_listen.is_none() {
+        anyhow::bail!("--signaling needs --http-listen");
+    }
+
+    let opus_config = OpusEncoderConfig {
+        application: parsed.opus_application,
+        bit_rate: parsed.bit_rate as i32,
+        vbr: !parsed.cbr,
+        constrained_vbr: !parsed.unconstrained_vbr,
+        inband_fec: parsed.fec,
+        packet_loss_percent: parsed.packet_loss,
+        dtx: parsed.dtx,
+        complexity: parsed.complexity,
+        max_bandwidth: parsed.max_bandwidth,
+    };
+
+    let recorder = match &parsed.record_dir {
+        Some(directory) => Some(RecorderHandle::spawn(RecorderConfig {
+            directory: directory.clone(),
+            max_size: parsed.record_max_size * 1024 * 1024,
+            max_duration: Duration::from_secs(parsed.record_max_duration),
+            width: parsed.width,
+            height: parsed.height,
+            audio_channels: parsed.channels,
+            remote_audio_channels: parsed.record_remote.then_some(parsed.speaker_channels),
+            mode: if parsed.record_triggered {
+                RecordingMode::Triggered {
+                    pre_event: Duration::from_secs(parsed.pre_event),
+                    post_event: Duration::from_secs(parsed.post_event),
+                }
+            } else {
+                RecordingMode::Continuous
+            },
+        })?),
+        None => None,
+    };
+
+    let (snapshot, mut snapshot_requests) = SnapshotHandle::new();
+    let (signaling, signaling_messages) = parsed.signaling.then(SignalingHandle::new).unzip();
+    if let Some(address) = parsed.http_listen {
+        let state = ControlState {
+            recorder: recorder.clone(),
+            snapshot,
+            signaling: signaling.clone(),
+        };
+        tokio::spawn(async move {
+            if let Err(e) = control_server::serve(address, state).await {
+                println!("control server stopped: {e}");
+            }
+        });
+    }
+
+    let audio_processing_config = AudioProcessingConfig {
+        echo_cancellation: parsed.echo_cancellation,
+        noise_suppression: parsed.noise_suppression,
+        gain_control: parsed.auto_gain_control,
+    };
+    // shared by capture and playback to use the played audio as the echo reference
+    let audio_processor = if audio_processing_config.enabled() {
+        Some(AudioProcessor::new(
+            parsed.channels,
+            parsed.speaker_channels,
+            &audio_processing_config,
+        )?)
+    } else {
+        None
+    };
 
     // Create a MediaEngine object to configure the supported codec
     let mut m = MediaEngine::default();
 
-    media_engine(&mut m, &[parsed.speaker_sample_rate, parsed.sample_rate])?;
+    media_engine(
+        &mut m,
+        parsed.channels,
+        parsed.speaker_channels,
+        &opus_config,
+    )?;
 
     // Create a InterceptorRegistry. This is the user configurable RTP/RTCP Pipeline.
     // This provides NACKs, RTCP Reports and other features. If you use `webrtc.NewPeerConnection`
@@ -120,18 +575,70 @@ async fn main() -> Result<()> {
     // Use the default set of Interceptors
     registry = register_default_interceptors(registry, &mut m)?;
 
+    // Restrict where candidates are gathered
+    let mut setting_engine = SettingEngine::default();
+    if !parsed.ice_interface.is_empty() {
+        let interfaces = parsed.ice_interface.clone();
+        setting_engine.set_interface_filter(Box::new(move |name: &str| {
+            interfaces.iter().any(|x| x == name)
+        }));
+    }
+    if !parsed.ice_network.is_empty() {
+        let network_types = parsed
+            .ice_network
+            .iter()
+            .map(|network| match network {
+                IceNetwork::Ipv4 => NetworkType::Udp4,
+                IceNetwork::Ipv6 => NetworkType::Udp6,
+            })
+            .collect();
+        setting_engine.set_network_types(network_types);
+    }
+    if let Some((min, max)) = parsed.ice_udp_ports {
+        setting_engine.set_udp_network(UDPNetwork::Ephemeral(EphemeralUDP::new(min, max)?));
+    }
+    if !parsed.nat_1to1_ip.is_empty() {
+        setting_engine.set_nat_1to1_ips(parsed.nat_1to1_ip.clone(), RTCIceCandidateType::Host);
+    }
+
     // Create the API object with the MediaEngine
     let api = APIBuilder::new()
         .with_media_engine(m)
         .with_interceptor_registry(registry)
+        .with_setting_engine(setting_engine)
         .build();
 
-    // Prepare the configuration
+    // Prepare the configuration, with the credentials for TURN servers
+    let relay = matches!(parsed.ice_transport_policy, IceTransportPolicy::Relay);
+    if relay && !parsed.ice_server.iter().any(IceServer::is_turn) {
+        anyhow::bail!("--ice-transport-policy relay needs a turn: or turns: --ice-server");
+    }
+    let ice_servers = parsed
+        .ice_server
+        .iter()
+        .map(|server| {
+            let (username, credential) = match &server.credentials {
+                Some(credentials) => credentials.clone(),
+                None if server.is_turn() => (
+                    parsed.ice_username.clone().unwrap_or_default(),
+                    parsed.ice_credential.clone().unwrap_or_default(),
+                ),
+                None => Default::default(),
+            };
+            RTCIceServer {
+                urls: vec![server.url.clone()],
+                username,
+                credential,
+                ..Default::default()
+            }
+        })
+        .collect();
     let config = RTCConfiguration {
-        ice_servers: vec![RTCIceServer {
-            urls: vec!["stun:stun.l.google.com:19302".to_owned()],
-            ..Default::default()
-        }],
+        ice_servers,
+        ice_transport_policy: match parsed.ice_transport_policy {
+            IceTransportPolicy::All => RTCIceTransportPolicy::All,
+            IceTransportPolicy::Relay => RTCIceTransportPolicy::Relay,
+        },
         ..Default::default()
     };
 
@@ -149,31 +656,243 @@ async fn main() -> Result<()> {
 
     let connected = Arc::new(std::sync::atomic::AtomicBool::new(true));
 
+    // voice activity of captured audio, for anything interested in whether we're talking
+    let (voice_events, mut voice_events_rx) = broadcast::channel::<VoiceActivityEvent>(16);
+    tokio::spawn(async move {
+        loop {
+            let event = match voice_events_rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    println!("missed {missed} voice activity events");
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            match event {
+                VoiceActivityEvent::Started(level) => {
+                    println!("voice activity started (-{} dBov)", level.level)
+                }
+                VoiceActivityEvent::Stopped => println!("voice activity stopped"),
+            }
+        }
+    });
+
+    // motion in the camera picture, for recording and anything else interested
+    let (motion_events, mut motion_events_rx) = broadcast::channel::<MotionEvent>(16);
     {
-        // Create a video track
-        let video_track = Arc::new(TrackLocalStaticSample::new(
-            RTCRtpCodecCapability {
-                mime_type: MIME_TYPE_H264.to_owned(),
-                ..Default::default()
-            },
-            "video".to_owned(),
-            "webrtc-rs".to_owned(),
-        ));
+        let recorder = recorder.clone().filter(|_| parsed.motion_trigger);
+        tokio::spawn(async move {
+            loop {
+                let event = match motion_events_rx.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        println!("missed {missed} motion events");
+                        // whether motion goes on is unknown, so the event ends after
+                        // the post-event time unless motion starts again
+                        if let Some(recorder) = &recorder {
+                            recorder.release("motion");
+                        }
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                match event {
+                    MotionEvent::Started { zone, area } => {
+                        println!("motion started in zone {zone} ({:.1}%)", area * 100.0)
+                    }
+                    MotionEvent::Stopped => println!("motion stopped"),
+                }
+                // the event is recorded until the post-event time after motion stops
+                if let Some(recorder) = &recorder {
+                    match event {
+                        MotionEvent::Started { .. } => recorder.hold("motion"),
+                        MotionEvent::Stopped => recorder.release("motion"),
+                    }
+                }
+            }
+        });
+    }
+
+    // commands of the viewer over the control data channel, run by the camera
+    let (camera_control, mut camera_commands) = CameraControlHandle::new();
+    {
+        let data_channel = peer_connection
+            .create_data_channel(
+                CONTROL_CHANNEL_LABEL,
+                Some(RTCDataChannelInit {
+                    negotiated: Some(CONTROL_CHANNEL_ID),
+                    ..Default::default()
+                }),
+            )
+            .await?;
+
+        // the handler is owned by the data channel itself
+        let weak_channel = Arc::downgrade(&data_channel);
+        let recorder = recorder.clone();
+        let camera_control = camera_control.clone();
+        data_channel.on_message(Box::new(move |message: DataChannelMessage| {
+            let data_channel = weak_channel.clone();
+            let recorder = recorder.clone();
+            let camera_control = camera_control.clone();
+            Box::pin(async move {
+                let response = match serde_json::from_slice::<Request>(&message.data) {
+                    Ok(request) => {
+                        let result = match request.command {
+                            Command::Trigger => match &recorder {
+                                Some(recorder) => {
+                                    recorder.trigger("data channel");
+                                    Ok(Reply::default())
+                                }
+                                None => Err(io::Error::new(
+                                    io::ErrorKind::Unsupported,
+                                    "recording is not enabled",
+                                )),
+                            },
+                            command => camera_control.execute(command).await,
+                        };
+                        Response::new(request.id, result)
+                    }
+                    Err(e) => {
+                        Response::new(None, Err(io::Error::new(io::ErrorKind::InvalidInput, e)))
+                    }
+                };
+                if let Some(data_channel) = data_channel.upgrade() {
+                    send_json(&data_channel, &response).await;
+                }
+            })
+        }));
+
+        let mut motion_events_rx = motion_events.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let event = match motion_events_rx.recv().await {
+                    Ok(event) => event,
+                    // the viewer gets the events from now on
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                let event = match event {
+                    MotionEvent::Started { zone, area } => Event::MotionStarted { zone, area },
+                    MotionEvent::Stopped => Event::MotionStopped,
+                };
+                send_json(&data_channel, &event).await;
+            }
+        });
+    }
+
+    // changes of the configuration file which apply without restarting
+    if let Some(path) = parsed.config.clone() {
+        let camera_control = camera_control.clone();
+        tokio::spawn(async move {
+            let mut hangup = signal(SignalKind::hangup())?;
+            let mut matches = matches;
+            while hangup.recv().await.is_some() {
+                match reload_config(&path, &matches, &camera_control).await {
+                    Ok(reloaded) => matches = reloaded,
+                    Err(e) => println!("failed to reload {}: {e:#}", path.display()),
+                }
+            }
+            Result::<()>::Ok(())
+        });
+    }
+
+    {
+        // Create a video track, or a track per simulcast layer
+        let codec = RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_H264.to_owned(),
+            ..Default::default()
+        };
+        let video_tracks = if parsed.simulcast_layers > 1 {
+            SIMULCAST_RIDS[..parsed.simulcast_layers as usize]
+                .iter()
+                .map(|rid| {
+                    Arc::new(TrackLocalStaticSample::new_with_rid(
+                        codec.clone(),
+                        "video".to_owned(),
+                        rid.to_string(),
+                        "webrtc-rs".to_owned(),
+                    ))
+                })
+                .collect::<Vec<_>>()
+        } else {
+            vec![Arc::new(TrackLocalStaticSample::new(
+                codec,
+                "video".to_owned(),
+                "webrtc-rs".to_owned(),
+            ))]
+        };
 
         let connected = connected.clone();
+        let recorder = recorder.clone();
 
         // Add this newly created track to the PeerConnection
         let rtp_sender = peer_connection
-            .add_track(Arc::clone(&video_track) as Arc<dyn TrackLocal + Send + Sync>)
+            .add_track(Arc::clone(&video_tracks[0]) as Arc<dyn TrackLocal + Send + Sync>)
             .await?;
+        // the other layers are sent by the same sender, told apart by RID
+        for video_track in &video_tracks[1..] {
+            rtp_sender
+                .add_encoding(Arc::clone(video_track) as Arc<dyn TrackLocal + Send + Sync>)
+                .await?;
+        }
         let rtp_sender_1 = rtp_sender.clone();
 
         // Read incoming RTCP packets
         // Before these packets are returned they are processed by interceptors. For things
         // like NACK this needs to be called.
+        // Receiver reports also tell the packet loss to adapt the resolution to.
+        let (stream_sizes_tx, stream_sizes) = oneshot::channel();
+        let (adaptive_resolution, width, height) =
+            (parsed.adaptive_resolution, parsed.width, parsed.height);
+        let camera_control = camera_control.clone();
         tokio::spawn(async move {
-            let mut rtcp_buf = vec![0u8; 1500];
-            while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
+            // the sizes are known once the camera is set up
+            let mut adapter = match adaptive_resolution {
+                true => match stream_sizes.await {
+                    Ok(Some(sizes)) => Some(ResolutionAdapter::with_sizes(width, height, &sizes)),
+                    Ok(None) => Some(ResolutionAdapter::new(width, height)),
+                    Err(_) => None,
+                },
+                false => None,
+            };
+            // the reports of other senders, like the audio one, don't tell about the video
+            let video_ssrcs: Vec<u32> = rtp_sender
+                .get_parameters()
+                .await
+                .encodings
+                .iter()
+                .map(|encoding| encoding.ssrc)
+                .collect();
+            while let Ok((packets, _)) = rtp_sender.read_rtcp().await {
+                let Some(adapter) = &mut adapter else {
+                    continue;
+                };
+                for packet in packets {
+                    let Some(report) = packet.as_any().downcast_ref::<ReceiverReport>() else {
+                        continue;
+                    };
+                    for reception in &report.reports {
+                        if !video_ssrcs.contains(&reception.ssrc) {
+                            continue;
+                        }
+                        let fraction_lost = reception.fraction_lost as f32 / 256.0;
+                        let Some((width, height)) =
+                            adapter.on_report(fraction_lost, Instant::now())
+                        else {
+                            continue;
+                        };
+                        println!("switching video to {width}x{height} for packet loss");
+                        let command = Command::Reconfigure {
+                            width,
+                            height,
+                            fps: None,
+                        };
+                        if let Err(e) = camera_control.execute(command).await {
+                            println!("failed to switch video resolution: {e}");
+                        }
+                    }
+                }
+            }
             Result::<()>::Ok(())
         });
 
@@ -188,6 +907,54 @@ async fn main() -> Result<()> {
                 &parsed.camera_fourcc.0,
                 b"H264",
             )?;
+            if parsed.capture_width.is_some()
+                || parsed.capture_height.is_some()
+                || parsed.stream_fourcc.is_some()
+            {
+                capture.set_scaler(
+                    parsed.capture_width.unwrap_or(parsed.width),
+                    parsed.capture_height.unwrap_or(parsed.height),
+                    &parsed.stream_fourcc.unwrap_or(parsed.camera_fourcc).0,
+                    parsed.scaler_device,
+                )?;
+            }
+            if let Some(path) = &parsed.privacy_masks {
+                capture.set_privacy_masks(privacy_mask::load(path)?)?;
+            }
+            if !parsed.overlay.is_empty() || !parsed.overlay_shape.is_empty() {
+                let overlay = Overlay::new(
+                    parsed.overlay,
+                    parsed.overlay_shape,
+                    capture.camera_name(),
+                    parsed.overlay_scale,
+                    parsed.overlay_background,
+                );
+                capture.set_overlay(overlay);
+            }
+            for layer in 1..parsed.simulcast_layers {
+                capture.add_layer(parsed.encoder_device, 1 << layer)?;
+            }
+            if let Some(bitrate) = parsed.video_bitrate {
+                capture.set_bitrate(bitrate)?;
+            }
+            if parsed.motion_detection {
+                let detector = MotionDetector::new(MotionConfig {
+                    zones: parsed.motion_zone,
+                    sensitivity: parsed.motion_sensitivity,
+                    min_area: parsed.motion_min_area / 100.0,
+                });
+                capture.set_motion_detector(detector, motion_events, parsed.motion_keyframe);
+            }
+            if parsed.adaptive_resolution {
+                match capture.stream_sizes() {
+                    Ok(sizes) => {
+                        let _ = stream_sizes_tx.send(sizes);
+                    }
+                    Err(e) => {
+                        println!("resolution won't adapt, the camera doesn't tell its sizes: {e}")
+                    }
+                }
+            }
 
             // Wait for connection established
             notify_video.notified().await;
@@ -199,10 +966,38 @@ async fn main() -> Result<()> {
             // It is important to use a time.Ticker instead of time.Sleep because
             // * avoids accumulating skew, just calling time.Sleep didn't compensate for the time spent parsing the data
             // * works around latency issues with Sleep
-            let interval = Duration::from_secs(1) / parsed.fps;
+            let mut interval = Duration::from_secs(1) / capture.fps();
             let mut ticker = tokio::time::interval(interval);
             while connected.load(std::sync::atomic::Ordering::Relaxed) {
-                let buffer = capture.take_frame().await?;
+                while let Ok(reply) = snapshot_requests.try_recv() {
+                    capture.request_snapshot(reply);
+                }
+                while let Ok((command, reply)) = camera_commands.try_recv() {
+                    let size = (capture.width(), capture.height());
+                    let masks_changed = matches!(command, Command::SetPrivacyMasks { .. });
+                    let result = capture.execute(command);
+                    if let (true, Ok(_), Some(path)) =
+                        (masks_changed, &result, &parsed.privacy_masks)
+                    {
+                        if let Err(e) = privacy_mask::save(path, capture.privacy_masks()) {
+                            println!("failed to save privacy masks: {e}");
+                        }
+                    }
+                    let _ = reply.send(result);
+                    if (capture.width(), capture.height()) != size {
+                        if let Some(recorder) = &recorder {
+                            recorder.resolution(capture.width(), capture.height());
+                        }
+                    }
+                    if Duration::from_secs(1) / capture.fps() != interval {
+                        interval = Duration::from_secs(1) / capture.fps();
+                        ticker = tokio::time::interval(interval);
+                    }
+                }
+                let access_units = capture.take_frame().await?;
+                if let Some(recorder) = &recorder {
+                    recorder.video(&access_units[0]);
+                }
 
                 /*println!(
                     "PictureOrderCount={}, ForbiddenZeroBit={}, RefIdc={}, UnitType={}, data={}",
@@ -213,15 +1008,17 @@ async fn main() -> Result<()> {
                     nal.data.len()
                 );*/
 
-                let mut h264 = H264Parser::new(buffer.as_slice());
-                while let Some(nal) = h264.next_buffer()? {
-                    video_track
-                        .write_sample(&Sample {
-                            data: Vec::from(nal).into(),
-                            duration: interval,
-                            ..Default::default()
-                        })
-                        .await?;
+                for (video_track, access_unit) in video_tracks.iter().zip(&access_units) {
+                    let mut h264 = H264Parser::new(access_unit);
+                    while let Some(nal) = h264.next_buffer()? {
+                        video_track
+                            .write_sample(&Sample {
+                                data: Vec::from(nal).into(),
+                                duration: interval,
+                                ..Default::default()
+                            })
+                            .await?;
+                    }
                 }
 
                 let _ = ticker.tick().await;
@@ -238,6 +1035,9 @@ async fn main() -> Result<()> {
 
     {
         let connected = connected.clone();
+        let audio_processor = audio_processor.clone();
+        let voice_events = voice_events.clone();
+        let recorder = recorder.clone();
 
         // Create a audio track
         let audio_track = Arc::new(TrackLocalStaticSample::new(
@@ -264,47 +1064,98 @@ async fn main() -> Result<()> {
         });
 
         tokio::spawn(async move {
-            let mut capture = MonauralAudioCapture::new(
-                &parsed.audio_device,
-                parsed.sample_rate,
-                parsed.bit_rate as i32,
-                parsed.frame_ms,
-            )?;
+            match &parsed.audio_file {
+                Some(path) if is_ogg_opus(path) => {
+                    let source = OggOpusSource::open(path)?;
+                    notify_audio.notified().await;
+                    println!("play audio from disk file {}", path.display());
+                    send_audio(
+                        source,
+                        &audio_track,
+                        &connected,
+                        &voice_events,
+                        recorder.as_ref(),
+                    )
+                    .await
+                }
+                Some(path) => {
+                    let source =
+                        WavSource::open(path, parsed.channels, &opus_config, parsed.frame_ms)?;
+                    notify_audio.notified().await;
+                    println!("play audio from disk file {}", path.display());
+                    send_audio(
+                        source,
+                        &audio_track,
+                        &connected,
+                        &voice_events,
+                        recorder.as_ref(),
+                    )
+                    .await
+                }
+                None => {
+                    let capture = AudioCapture::new(
+                        &parsed.audio_device,
+                        parsed.sample_rate,
+                        parsed.channels,
+                        &opus_config,
+                        parsed.frame_ms,
+                        parsed.xrun_policy,
+                        audio_processor,
+                    )?;
+                    // Wait for connection established
+                    notify_audio.notified().await;
+                    println!("play audio from device {}", parsed.audio_device);
+                    send_audio(
+                        capture,
+                        &audio_track,
+                        &connected,
+                        &voice_events,
+                        recorder.as_ref(),
+                    )
+                    .await
+                }
+            }
+        });
+    }
 
+    // decoded tracks are mixed into the speaker device or a WAV file,
+    // while an Ogg Opus file stores packets of the first track as is
+    let speaker_file = parsed.speaker_file;
+    let mixer = match &speaker_file {
+        Some(path) if is_ogg_opus(path) => None,
+        _ => Some(AudioMixer::new(parsed.speaker_channels)),
+    };
+    if let Some(mixer) = mixer.clone() {
+        let notify_speaker = notify_tx.clone();
+        let speaker_file = speaker_file.clone();
+        let speaker_audio_device = parsed.speaker_audio_device;
+        tokio::spawn(async move {
             // Wait for connection established
-            notify_audio.notified().await;
-
-            println!("play audio from disk file test.ogg");
-
-            // It is important to use a time.Ticker instead of time.Sleep because
-            // * avoids accumulating skew, just calling time.Sleep didn't compensate for the time spent parsing the data
-            // * works around latency issues with Sleep
-            let mut ticker = tokio::time::interval(Duration::from_millis(parsed.frame_ms as u64));
-
-            // Keep track of last granule, the difference is the amount of samples in the buffer
-            while connected.load(std::sync::atomic::Ordering::Relaxed) {
-                let (duration, encoded_buffer) = capture.capture_frame()?;
-
-                // The amount of samples is the difference between the last and current timestamp
-                audio_track
-                    .write_sample(&Sample {
-                        data: Vec::from(encoded_buffer).into(),
-                        duration,
-                        ..Default::default()
-                    })
-                    .await?;
-
-                let _ = ticker.tick().await;
+            notify_speaker.notified().await;
+            match speaker_file {
+                Some(path) => {
+                    let sink = WavSink::create(&path, parsed.speaker_channels)?;
+                    mixer.run(sink, audio_processor).await?;
+                }
+                None => {
+                    let playback = AudioPlayback::new(
+                        &speaker_audio_device,
+                        parsed.speaker_sample_rate,
+                        parsed.speaker_channels,
+                        parsed.xrun_policy,
+                    )?;
+                    mixer.run(playback, audio_processor).await?;
+                }
             }
-
-            drop(capture); // close
-
             Result::<()>::Ok(())
         });
     }
+    let recording = Arc::new(AtomicBool::new(false));
+    let remote_recorder = recorder.clone().filter(|_| parsed.record_remote);
+    let remote_recorded = Arc::new(AtomicBool::new(false));
+    let remote_video_file = parsed.remote_video_file;
 
     let pc = Arc::downgrade(&peer_connection);
-    let speaker_audio_device = parsed.speaker_audio_device;
     peer_connection.on_track(Box::new(move |track, _, _| {
         // Send a PLI on an interval so that the publisher is pushing a keyframe every rtcpPLIInterval
         let media_ssrc = track.ssrc();
@@ -330,24 +1181,86 @@ async fn main() -> Result<()> {
             }
         });
 
-        let speaker_audio_device = speaker_audio_device.clone();
+        let speaker_file = speaker_file.clone();
+        let remote_video_file = remote_video_file.clone();
+        let mixer = mixer.clone();
+        let recording = recording.clone();
+        let recorder = remote_recorder.clone();
+        let remote_recorded = remote_recorded.clone();
+        let track_id = track.id();
+        let gain = parsed
+            .track_gain
+            .iter()
+            .find(|(id, _)| *id == track_id)
+            .map_or(1.0, |(_, gain)| *gain);
         Box::pin(async move {
             let codec = track.codec();
             let mime_type = codec.capability.mime_type.to_lowercase();
             if mime_type == MIME_TYPE_OPUS.to_lowercase() {
-                println!("Got Opus track, Playing (48 kHz, 1 channels)");
-                tokio::spawn(async move {
-                    let mut playback = MonauralAudioPlayback::new(
-                        &speaker_audio_device,
+                let jitter_buffer = JitterBuffer::new(
+                    codec.capability.clock_rate,
+                    parsed.jitter_buffer_min,
+                    parsed.jitter_buffer_max,
+                );
+
+                if let Some(mixer) = mixer {
+                    let recorder = recorder.filter(|_| {
+                        !remote_recorded.swap(true, std::sync::atomic::Ordering::Relaxed)
+                    });
+                    println!(
+                        "Got Opus track {}, Mixing ({} Hz, {} channels)",
+                        track.id(),
                         parsed.speaker_sample_rate,
-                    )?;
-
-                    loop {
-                        let (rtp_packet, _) = track.read_rtp().await?;
-                        playback.play_frame(&rtp_packet.payload)?;
+                        parsed.speaker_channels
+                    );
+                    tokio::spawn(async move {
+                        let input =
+                            mixer.add_input(jitter_buffer, codec.capability.clock_rate, gain)?;
+                        receive_remote_audio(track, input, recorder).await
+                    });
+                } else if let Some(path) = speaker_file {
+                    if recording.swap(true, std::sync::atomic::Ordering::Relaxed) {
+                        println!("Got Opus track {}, ignored: already recording", track.id());
+                        return;
+                    }
+                    println!("Got Opus track {}, Recording", track.id());
+                    tokio::spawn(async move {
+                        let sink = OggOpusSink::create(&path, parsed.speaker_channels)?;
+                        play_remote_audio(track, sink, jitter_buffer).await
+                    });
+                }
+            }
+            if mime_type == MIME_TYPE_H264.to_lowercase() {
+                tokio::spawn(async move {
+                    let width = parsed.remote_video_width;
+                    let height = parsed.remote_video_height;
+                    match (
+                        remote_video_file,
+                        parsed.remote_video_device,
+                        parsed.remote_video_decoder,
+                    ) {
+                        (Some(path), _, _) => {
+                            println!("Got H.264 track, Writing to {}", path.display());
+                            let sink = AnnexBFile::create(&path)?;
+                            receive_remote_video(track, sink).await
+                        }
+                        (None, Some(device), Some(decoder)) => {
+                            println!("Got H.264 track, Decoding to video{device}");
+                            let fourcc = &parsed.remote_video_fourcc.0;
+                            let output = V4l2Output::new(device, width, height, fourcc)?;
+                            let sink = V4l2Decoder::new(decoder, output, width, height, fourcc)?;
+                            receive_remote_video(track, sink).await
+                        }
+                        (None, Some(device), None) => {
+                            println!("Got H.264 track, Writing to video{device}");
+                            let sink = V4l2Output::new(device, width, height, b"H264")?;
+                            receive_remote_video(track, sink).await
+                        }
+                        (None, None, _) => {
+                            println!("Got H.264 track, ignored: no remote video output");
+                            Ok(())
+                        }
                     }
-
-                    Result::<()>::Ok(())
                 });
             }
         })
@@ -381,33 +1294,91 @@ async fn main() -> Result<()> {
         Box::pin(async {})
     }));
 
-    println!("enter offer:");
-    // Wait for the offer to be pasted
-    let offer = io::read_to_string(io::stdin())?;
-    let offer = RTCSessionDescription::offer(offer)?;
+    if let (Some(signaling), Some(mut signaling_messages), Some(address)) =
+        (signaling, signaling_messages, parsed.http_listen)
+    {
+        // Send our candidates as they are gathered, None when gathering is complete
+        let candidate_signaling = signaling.clone();
+        peer_connection.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
+            let signaling = candidate_signaling.clone();
+            Box::pin(async move {
+                match candidate.map(|x| x.to_json()).transpose() {
+                    Ok(candidate) => signaling.send(SignalingMessage::Candidate { candidate }),
+                    Err(e) => println!("failed to send ICE candidate: {e}"),
+                }
+            })
+        }));
+
+        println!("waiting for offer on ws://{address}/signaling");
+        let offer = loop {
+            match signaling_messages.recv().await {
+                Some(SignalingMessage::Offer { sdp }) => break sdp,
+                Some(message) => println!("ignored signaling message before offer: {message:?}"),
+                None => anyhow::bail!("signaling stopped"),
+            }
+        };
+
+        // Set the remote SessionDescription
+        peer_connection
+            .set_remote_description(RTCSessionDescription::offer(offer)?)
+            .await?;
+
+        // Create an answer, sent before the candidates gathered for it
+        let answer = peer_connection.create_answer(None).await?;
+        signaling.send(SignalingMessage::Answer {
+            sdp: answer.sdp.clone(),
+        });
+
+        // Sets the LocalDescription, and starts our UDP listeners
+        peer_connection.set_local_description(answer).await?;
+
+        // Add the candidates of the viewer as they arrive
+        let pc = Arc::downgrade(&peer_connection);
+        tokio::spawn(async move {
+            while let Some(message) = signaling_messages.recv().await {
+                let Some(pc) = pc.upgrade() else {
+                    break;
+                };
+                match message {
+                    // an empty candidate ends the remote candidates
+                    SignalingMessage::Candidate { candidate } => {
+                        if let Err(e) = pc.add_ice_candidate(candidate.unwrap_or_default()).await {
+                            println!("failed to add ICE candidate: {e}");
+                        }
+                    }
+                    message => println!("ignored signaling message: {message:?}"),
+                }
+            }
+        });
+    } else {
+        println!("enter offer:");
+        // Wait for the offer to be pasted
+        let offer = io::read_to_string(io::stdin())?;
+        let offer = RTCSessionDescription::offer(offer)?;
 
-    // Set the remote SessionDescription
-    peer_connection.set_remote_description(offer).await?;
+        // Set the remote SessionDescription
+        peer_connection.set_remote_description(offer).await?;
 
-    // Create an answer
-    let answer = peer_connection.create_answer(None).await?;
+        // Create an answer
+        let answer = peer_connection.create_answer(None).await?;
 
-    // Create channel that is blocked until ICE Gathering is complete
-    let mut gather_complete = peer_connection.gathering_complete_promise().await;
+        // Create channel that is blocked until ICE Gathering is complete
+        let mut gather_complete = peer_connection.gathering_complete_promise().await;
 
-    // Sets the LocalDescription, and starts our UDP listeners
-    peer_connection.set_local_description(answer).await?;
+        // Sets the LocalDescription, and starts our UDP listeners
+        peer_connection.set_local_description(answer).await?;
 
-    // Block until ICE Gathering is complete, disabling trickle ICE
-    // we do this because we only can exchange one signaling message
-    // in a production application you should exchange ICE Candidates via OnICECandidate
-    let _ = gather_complete.recv().await;
+        // Block until ICE Gathering is complete, disabling trickle ICE
+        // we do this because we only can exchange one signaling message over stdin,
+        // candidates are exchanged as gathered with --signaling
+        let _ = gather_complete.recv().await;
 
-    // Output the answer in base64 so we can paste it in browser
-    if let Some(local_desc) = peer_connection.local_description().await {
-        println!("{}", local_desc.sdp);
-    } else {
-        println!("generate local_description failed!");
+        // Output the answer in base64 so we can paste it in browser
+        if let Some(local_desc) = peer_connection.local_description().await {
+            println!("{}", local_desc.sdp);
+        } else {
+            println!("generate local_description failed!");
+        }
     }
 
     println!("Press ctrl-c to stop");
@@ -427,7 +1398,228 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-fn media_engine(m: &mut MediaEngine, audio_sample_rates: &[u32]) -> Result<(), webrtc::Error> {
+/// Reads the configuration file again and applies the changes of the video bitrate,
+/// the overlay and the privacy masks. Other changes are reported to need a restart.
+///
+/// Changes are queued for the camera, which takes them once it serves commands: the video
+/// only starts with a viewer. Fails without queueing them all if the camera is busy for
+/// [RELOAD_TIMEOUT], so they are compared with the same options on the next reload.
+async fn reload_config(
+    path: &Path,
+    old: &ArgMatches,
+    camera_control: &CameraControlHandle,
+) -> Result<ArgMatches> {
+    let matches = config::reload::<Cli>(path)?;
+    let parsed = Cli::from_arg_matches(&matches)?;
+    let changes = config::changes::<Cli>(old, &matches);
+    let mut commands = Vec::new();
+    let mut overlay_changed = false;
+    for id in &changes {
+        match id.as_str() {
+            "video_bitrate" => {
+                if let Some(bitrate) = parsed.video_bitrate {
+                    commands.push(Command::Bitrate { bitrate });
+                }
+            }
+            "overlay" | "overlay_shape" | "overlay_scale" | "overlay_background" => {
+                overlay_changed = true
+            }
+            _ => println!("{id} changed in {}, restart to apply", path.display()),
+        }
+    }
+    if overlay_changed {
+        let command = Command::SetOverlay {
+            texts: parsed.overlay,
+            shapes: parsed.overlay_shape,
+            scale: parsed.overlay_scale,
+            background: parsed.overlay_background,
+        };
+        commands.push(command);
+    }
+    // the masks may have been edited in their own file, saved to the first one when changed
+    let masks_moved = changes.iter().any(|id| id == "privacy_masks");
+    if let Some(masks_path) = parsed.privacy_masks.filter(|_| !masks_moved) {
+        let masks = privacy_mask::load(&masks_path)?;
+        commands.push(Command::SetPrivacyMasks { masks });
+    }
+    let mut replies = Vec::new();
+    for command in commands {
+        let reply = tokio::time::timeout(RELOAD_TIMEOUT, camera_control.queue(command))
+            .await
+            .map_err(|_| anyhow::anyhow!("camera is busy"))??;
+        replies.push(reply);
+    }
+    for reply in replies {
+        match tokio::time::timeout(RELOAD_TIMEOUT, reply).await {
+            Ok(Ok(result)) => {
+                result?;
+            }
+            Ok(Err(_)) => anyhow::bail!("camera stopped"),
+            Err(_) => {
+                println!("changes of {} apply when the video starts", path.display());
+                break;
+            }
+        }
+    }
+    println!("reloaded {}", path.display());
+    Ok(matches)
+}
+
+/// Sends packets from the source to the track until disconnected.
+///
+/// The audio level of each packet is sent with the `ssrc-audio-level` header extension,
+/// and changes of voice activity are sent to `voice_events`.
+async fn send_audio(
+    mut source: impl AudioSource,
+    audio_track: &TrackLocalStaticSample,
+    connected: &AtomicBool,
+    voice_events: &broadcast::Sender<VoiceActivityEvent>,
+    recorder: Option<&RecorderHandle>,
+) -> Result<()> {
+    let mut voice = false;
+    // capture_frame waits for the device or the file pacing, so the source paces this loop
+    while connected.load(std::sync::atomic::Ordering::Relaxed) {
+        let (duration, encoded_buffer) = source.capture_frame().await?;
+        if let Some(recorder) = recorder {
+            recorder.audio(encoded_buffer);
+        }
+
+        // The amount of samples is the difference between the last and current timestamp
+        let sample = Sample {
+            data: Vec::from(encoded_buffer).into(),
+            duration,
+            ..Default::default()
+        };
+
+        let Some(audio_level) = source.audio_level() else {
+            audio_track.write_sample(&sample).await?;
+            continue;
+        };
+
+        if audio_level.voice != voice {
+            voice = audio_level.voice;
+            let event = if voice {
+                VoiceActivityEvent::Started(audio_level)
+            } else {
+                VoiceActivityEvent::Stopped
+            };
+            // no one may be listening
+            let _ = voice_events.send(event);
+        }
+
+        let extension = HeaderExtension::AudioLevel(AudioLevelExtension {
+            level: audio_level.level,
+            voice: audio_level.voice,
+        });
+        audio_track
+            .write_sample_with_extensions(&sample, &[extension])
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Reassembles access units from RTP packets of a H.264 track and writes them to `sink`.
+async fn receive_remote_video(track: Arc<TrackRemote>, mut sink: impl VideoSink) -> Result<()> {
+    // packets later than this many packets are dropped
+    const MAX_LATE: u16 = 512;
+    let clock_rate = track.codec().capability.clock_rate;
+    let mut sample_builder = SampleBuilder::new(MAX_LATE, H264Packet::default(), clock_rate);
+    loop {
+        // frames of the sink, like decoded ones, are handled as soon as they are ready
+        let rtp_packet = tokio::select! {
+            result = track.read_rtp() => result?.0,
+            ready = sink.ready() => {
+                ready?;
+                sink.forward().await?;
+                continue;
+            }
+        };
+        sample_builder.push(rtp_packet);
+        // without AVC mode the depacketizer outputs Annex-B with start codes
+        while let Some(sample) = sample_builder.pop() {
+            sink.write_access_unit(&sample.data).await?;
+        }
+    }
+}
+
+/// Receives RTP packets of a track into the mixer until the track ends.
+async fn receive_remote_audio(
+    track: Arc<TrackRemote>,
+    input: MixerInputHandle,
+    recorder: Option<RecorderHandle>,
+) -> Result<()> {
+    loop {
+        let (rtp_packet, _) = track.read_rtp().await?;
+        if let Some(recorder) = &recorder {
+            recorder.remote_audio(&rtp_packet.payload);
+        }
+        input.push(
+            rtp_packet.header.sequence_number,
+            rtp_packet.header.timestamp,
+            rtp_packet.payload.to_vec(),
+            Instant::now(),
+        );
+    }
+}
+
+/// Receives RTP packets into the jitter buffer in a task,
+/// and plays frames taken from the jitter buffer with the pace of the audio.
+async fn play_remote_audio(
+    track: Arc<TrackRemote>,
+    mut playback: impl AudioSink,
+    jitter_buffer: JitterBuffer,
+) -> Result<()> {
+    let clock_rate = track.codec().capability.clock_rate;
+    let jitter_buffer = Arc::new(std::sync::Mutex::new(jitter_buffer));
+
+    let receiving_buffer = jitter_buffer.clone();
+    tokio::spawn(async move {
+        loop {
+            let (rtp_packet, _) = track.read_rtp().await?;
+            receiving_buffer.lock().unwrap().push(
+                rtp_packet.header.sequence_number,
+                rtp_packet.header.timestamp,
+                rtp_packet.payload.to_vec(),
+                Instant::now(),
+            );
+        }
+
+        Result::<()>::Ok(())
+    });
+
+    let mut deadline = tokio::time::Instant::now();
+    // the receiving task holds the other reference until the track ends
+    while Arc::strong_count(&jitter_buffer) > 1 {
+        let playout = jitter_buffer.lock().unwrap().pop();
+        let duration = match playout {
+            // buffering, check again later
+            None => Duration::from_millis(5),
+            Some(Playout::Packet(packet)) => playback.play_frame(&packet).await?,
+            Some(Playout::Fec { next, samples }) => {
+                let duration = Duration::from_secs(samples as u64) / clock_rate;
+                playback.recover_frame(&next, duration).await?;
+                duration
+            }
+            Some(Playout::Conceal { samples }) => {
+                let duration = Duration::from_secs(samples as u64) / clock_rate;
+                playback.conceal_frame(duration).await?;
+                duration
+            }
+        };
+        deadline += duration;
+        tokio::time::sleep_until(deadline).await;
+    }
+
+    Ok(())
+}
+
+fn media_engine(
+    m: &mut MediaEngine,
+    send_channels: u32,
+    receive_channels: u32,
+    opus_config: &OpusEncoderConfig,
+) -> Result<(), webrtc::Error> {
     let fmt_line = [
         (
             102,
@@ -468,22 +1660,64 @@ fn media_engine(m: &mut MediaEngine, audio_sample_rates: &[u32]) -> Result<(), w
         )?;
     }
 
-    for &sample_rates in audio_sample_rates {
-        m.register_codec(
-            RTCRtpCodecParameters {
-                capability: RTCRtpCodecCapability {
-                    mime_type: MIME_TYPE_OPUS.to_owned(),
-                    clock_rate: sample_rates,
-                    channels: 1,
-                    sdp_fmtp_line: "".to_owned(),
-                    rtcp_feedback: vec![],
-                },
-                payload_type: 111,
-                ..Default::default()
+    // devices of other rates are resampled from/to 48 kHz
+    m.register_codec(
+        RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_OPUS.to_owned(),
+                clock_rate: OPUS_SAMPLE_RATE,
+                channels: 2,
+                sdp_fmtp_line: opus_fmtp_line(send_channels, receive_channels, opus_config),
+                rtcp_feedback: vec![],
             },
-            RTPCodecType::Audio,
+            payload_type: 111,
+            ..Default::default()
+        },
+        RTPCodecType::Audio,
+    )?;
+
+    // MID and RID tell simulcast layers of the video sender apart (RFC 8852)
+    for uri in [SDES_MID_URI, SDES_RTP_STREAM_ID_URI] {
+        m.register_header_extension(
+            RTCRtpHeaderExtensionCapability {
+                uri: uri.to_owned(),
+            },
+            RTPCodecType::Video,
+            None,
         )?;
     }
 
+    // audio level and voice activity of sent packets (RFC 6464)
+    m.register_header_extension(
+        RTCRtpHeaderExtensionCapability {
+            uri: AUDIO_LEVEL_URI.to_owned(),
+        },
+        RTPCodecType::Audio,
+        None,
+    )?;
+
     Ok(())
 }
+
+/// Opus is always signaled as `opus/48000/2` (RFC 7587 section 7),
+/// so whether we send or want to receive stereo is told with fmtp parameters.
+fn opus_fmtp_line(
+    send_channels: u32,
+    receive_channels: u32,
+    opus_config: &OpusEncoderConfig,
+) -> String {
+    let mut params = vec![];
+    if opus_config.inband_fec {
+        params.push("useinbandfec=1");
+    }
+    if opus_config.dtx {
+        params.push("usedtx=1");
+    }
+    if receive_channels == 2 {
+        params.push("stereo=1");
+    }
+    if send_channels == 2 {
+        params.push("sprop-stereo=1");
+    }
+    params.join(";")
+}
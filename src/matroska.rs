@@ -0,0 +1,310 @@
+//! Minimal Matroska muxer for H.264 and Opus.
+//!
+//! The segment is written with unknown size and clusters are written as they're completed,
+//! so a file cut short by a crash or power loss is still playable up to the last cluster.
+
+use std::io;
+use std::io::Write;
+
+mod id {
+    pub const EBML: u32 = 0x1A45DFA3;
+    pub const EBML_VERSION: u32 = 0x4286;
+    pub const EBML_READ_VERSION: u32 = 0x42F7;
+    pub const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+    pub const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+    pub const DOC_TYPE: u32 = 0x4282;
+    pub const DOC_TYPE_VERSION: u32 = 0x4287;
+    pub const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+
+    pub const SEGMENT: u32 = 0x18538067;
+
+    pub const INFO: u32 = 0x1549A966;
+    pub const TIMESTAMP_SCALE: u32 = 0x2AD7B1;
+    pub const MUXING_APP: u32 = 0x4D80;
+    pub const WRITING_APP: u32 = 0x5741;
+
+    pub const TRACKS: u32 = 0x1654AE6B;
+    pub const TRACK_ENTRY: u32 = 0xAE;
+    pub const TRACK_NUMBER: u32 = 0xD7;
+    pub const TRACK_UID: u32 = 0x73C5;
+    pub const TRACK_TYPE: u32 = 0x83;
+    pub const FLAG_LACING: u32 = 0x9C;
+    pub const NAME: u32 = 0x536E;
+    pub const CODEC_ID: u32 = 0x86;
+    pub const CODEC_PRIVATE: u32 = 0x63A2;
+    pub const CODEC_DELAY: u32 = 0x56AA;
+    pub const SEEK_PRE_ROLL: u32 = 0x56BB;
+    pub const VIDEO: u32 = 0xE0;
+    pub const PIXEL_WIDTH: u32 = 0xB0;
+    pub const PIXEL_HEIGHT: u32 = 0xBA;
+    pub const AUDIO: u32 = 0xE1;
+    pub const SAMPLING_FREQUENCY: u32 = 0xB5;
+    pub const CHANNELS: u32 = 0x9F;
+
+    pub const CLUSTER: u32 = 0x1F43B675;
+    pub const TIMESTAMP: u32 = 0xE7;
+    pub const SIMPLE_BLOCK: u32 = 0xA3;
+}
+
+/// Timestamps are in milliseconds.
+const TIMESTAMP_SCALE: u64 = 1_000_000;
+
+/// Start a new cluster at least this often (ms) so little is lost if the file is cut short.
+/// It must also keep block timestamps relative to the cluster within i16.
+const MAX_CLUSTER_DURATION: u64 = 5_000;
+
+/// Opus decoders need 80 ms before a seek point to converge (RFC 7845 section 4.6).
+const OPUS_SEEK_PRE_ROLL_NS: u64 = 80_000_000;
+
+pub enum TrackCodec {
+    /// H.264 with an AVCDecoderConfigurationRecord
+    H264 {
+        avc_config: Vec<u8>,
+        width: u32,
+        height: u32,
+    },
+    Opus {
+        channels: u32,
+    },
+}
+
+pub struct TrackInfo {
+    pub name: &'static str,
+    pub codec: TrackCodec,
+}
+
+pub struct MatroskaWriter<W: Write> {
+    writer: W,
+    /// the cluster being built, written when the next one starts
+    cluster: Vec<u8>,
+    /// the timestamp of the cluster being built (ms), or None before the first block
+    cluster_timestamp: Option<u64>,
+    /// bytes written to `writer`
+    written: u64,
+    /// numbers of video tracks, whose keyframes start clusters
+    video_tracks: Vec<u64>,
+}
+
+impl<W: Write> MatroskaWriter<W> {
+    /// Writes the header and the tracks. Tracks are numbered from 1 in order.
+    pub fn new(mut writer: W, tracks: &[TrackInfo]) -> io::Result<Self> {
+        let mut header = Vec::new();
+
+        element(&mut header, id::EBML, |buf| {
+            uint(buf, id::EBML_VERSION, 1);
+            uint(buf, id::EBML_READ_VERSION, 1);
+            uint(buf, id::EBML_MAX_ID_LENGTH, 4);
+            uint(buf, id::EBML_MAX_SIZE_LENGTH, 8);
+            string(buf, id::DOC_TYPE, "matroska");
+            uint(buf, id::DOC_TYPE_VERSION, 4);
+            uint(buf, id::DOC_TYPE_READ_VERSION, 2);
+        });
+
+        write_id(&mut header, id::SEGMENT);
+        // unknown size, so that we don't have to seek back
+        header.extend_from_slice(&[0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+
+        element(&mut header, id::INFO, |buf| {
+            uint(buf, id::TIMESTAMP_SCALE, TIMESTAMP_SCALE);
+            let app = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+            string(buf, id::MUXING_APP, app);
+            string(buf, id::WRITING_APP, app);
+        });
+
+        element(&mut header, id::TRACKS, |buf| {
+            for (index, track) in tracks.iter().enumerate() {
+                element(buf, id::TRACK_ENTRY, |buf| {
+                    track_entry(buf, index as u64 + 1, track)
+                });
+            }
+        });
+
+        writer.write_all(&header)?;
+        writer.flush()?;
+
+        Ok(Self {
+            writer,
+            cluster: Vec::new(),
+            cluster_timestamp: None,
+            written: header.len() as u64,
+            video_tracks: tracks
+                .iter()
+                .zip(1..)
+                .filter(|(track, _)| matches!(track.codec, TrackCodec::H264 { .. }))
+                .map(|(_, number)| number)
+                .collect(),
+        })
+    }
+
+    /// Bytes written to the file so far, excluding the cluster being built
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Adds a frame of `track` (numbered from 1) at `timestamp` ms from the start of the file.
+    ///
+    /// A new cluster is started at video keyframes so that each cluster can be decoded alone.
+    pub fn write_frame(
+        &mut self,
+        track: u64,
+        timestamp: u64,
+        keyframe: bool,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let video_keyframe = keyframe && self.video_tracks.contains(&track);
+        let start_cluster = match self.cluster_timestamp {
+            None => true,
+            Some(cluster_timestamp) => {
+                (video_keyframe && timestamp > cluster_timestamp)
+                    || timestamp.saturating_sub(cluster_timestamp) >= MAX_CLUSTER_DURATION
+            }
+        };
+        if start_cluster {
+            self.flush_cluster()?;
+            self.cluster_timestamp = Some(timestamp);
+            uint(&mut self.cluster, id::TIMESTAMP, timestamp);
+        }
+        let cluster_timestamp = self.cluster_timestamp.unwrap_or(timestamp);
+
+        // frames of other tracks may come slightly before the cluster start
+        let relative = (timestamp as i64 - cluster_timestamp as i64)
+            .clamp(i16::MIN as i64, i16::MAX as i64) as i16;
+
+        let mut block = Vec::with_capacity(data.len() + 4);
+        write_size(&mut block, track);
+        block.extend_from_slice(&relative.to_be_bytes());
+        block.push(if keyframe { 0x80 } else { 0 });
+        block.extend_from_slice(data);
+
+        write_id(&mut self.cluster, id::SIMPLE_BLOCK);
+        write_size(&mut self.cluster, block.len() as u64);
+        self.cluster.extend_from_slice(&block);
+        Ok(())
+    }
+
+    /// Writes the cluster being built.
+    pub fn flush_cluster(&mut self) -> io::Result<()> {
+        if self.cluster.is_empty() {
+            return Ok(());
+        }
+        let mut header = Vec::new();
+        write_id(&mut header, id::CLUSTER);
+        write_size(&mut header, self.cluster.len() as u64);
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&self.cluster)?;
+        self.writer.flush()?;
+        self.written += (header.len() + self.cluster.len()) as u64;
+        self.cluster.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for MatroskaWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.flush_cluster();
+    }
+}
+
+fn track_entry(buf: &mut Vec<u8>, number: u64, track: &TrackInfo) {
+    uint(buf, id::TRACK_NUMBER, number);
+    uint(buf, id::TRACK_UID, number);
+    uint(buf, id::FLAG_LACING, 0);
+    string(buf, id::NAME, track.name);
+    match &track.codec {
+        TrackCodec::H264 {
+            avc_config,
+            width,
+            height,
+        } => {
+            uint(buf, id::TRACK_TYPE, 1);
+            string(buf, id::CODEC_ID, "V_MPEG4/ISO/AVC");
+            binary(buf, id::CODEC_PRIVATE, avc_config);
+            element(buf, id::VIDEO, |buf| {
+                uint(buf, id::PIXEL_WIDTH, *width as u64);
+                uint(buf, id::PIXEL_HEIGHT, *height as u64);
+            });
+        }
+        TrackCodec::Opus { channels } => {
+            uint(buf, id::TRACK_TYPE, 2);
+            string(buf, id::CODEC_ID, "A_OPUS");
+            binary(buf, id::CODEC_PRIVATE, &opus_head(*channels));
+            uint(buf, id::CODEC_DELAY, 0);
+            uint(buf, id::SEEK_PRE_ROLL, OPUS_SEEK_PRE_ROLL_NS);
+            element(buf, id::AUDIO, |buf| {
+                float(buf, id::SAMPLING_FREQUENCY, 48000.0);
+                uint(buf, id::CHANNELS, *channels as u64);
+            });
+        }
+    }
+}
+
+/// The identification header of Ogg Opus (RFC 7845 section 5.1), used as CodecPrivate
+fn opus_head(channels: u32) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1); // version
+    head.push(channels as u8);
+    head.extend_from_slice(&0u16.to_le_bytes()); // pre-skip
+    head.extend_from_slice(&48000u32.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+    head.push(0); // channel mapping family
+    head
+}
+
+/// Builds AVCDecoderConfigurationRecord (ISO/IEC 14496-15 section 5.2.4.1) from SPS and PPS.
+pub fn avc_decoder_configuration(sps: &[u8], pps: &[u8]) -> Vec<u8> {
+    let mut config = vec![
+        1,      // configurationVersion
+        sps[1], // AVCProfileIndication
+        sps[2], // profile_compatibility
+        sps[3], // AVCLevelIndication
+        0xFF,   // lengthSizeMinusOne = 3
+        0xE1,   // numOfSequenceParameterSets = 1
+    ];
+    config.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+    config.extend_from_slice(sps);
+    config.push(1); // numOfPictureParameterSets
+    config.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+    config.extend_from_slice(pps);
+    config
+}
+
+fn write_id(buf: &mut Vec<u8>, id: u32) {
+    // IDs include their length marker, so the significant bytes are written as is
+    let bytes = id.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&x| x == 0).count().min(3);
+    buf.extend_from_slice(&bytes[skip..]);
+}
+
+/// Writes a variable size integer with the shortest length.
+fn write_size(buf: &mut Vec<u8>, size: u64) {
+    // all ones is reserved for unknown size
+    let len = (1..=8).find(|len| size < (1 << (7 * len)) - 1).unwrap_or(8);
+    let marked = size | (1 << (7 * len));
+    buf.extend_from_slice(&marked.to_be_bytes()[8 - len..]);
+}
+
+fn element(buf: &mut Vec<u8>, id: u32, children: impl FnOnce(&mut Vec<u8>)) {
+    let mut body = Vec::new();
+    children(&mut body);
+    binary(buf, id, &body);
+}
+
+fn binary(buf: &mut Vec<u8>, id: u32, data: &[u8]) {
+    write_id(buf, id);
+    write_size(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+fn uint(buf: &mut Vec<u8>, id: u32, value: u64) {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&x| x == 0).count().min(7);
+    binary(buf, id, &bytes[skip..]);
+}
+
+fn float(buf: &mut Vec<u8>, id: u32, value: f64) {
+    binary(buf, id, &value.to_be_bytes());
+}
+
+fn string(buf: &mut Vec<u8>, id: u32, value: &str) {
+    binary(buf, id, value.as_bytes());
+}
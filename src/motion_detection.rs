@@ -0,0 +1,357 @@
+//! Motion detection on raw camera frames.
+//!
+//! Luma is averaged into a coarse grid, which is compared with the grid of the previous
+//! analyzed frame. Motion is reported when enough cells of a zone change, so sensor
+//! noise and small objects are ignored and the cost stays low on a Pi.
+
+use std::time::{Duration, Instant};
+
+/// Motion changes of the camera picture.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MotionEvent {
+    /// motion started in the zone with the index, changing the fraction of its area
+    Started {
+        zone: usize,
+        area: f32,
+    },
+    Stopped,
+}
+
+/// A rectangle of the picture, in fractions of its width and height.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Zone {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Zone {
+    pub const FULL: Zone = Zone {
+        x: 0.0,
+        y: 0.0,
+        width: 1.0,
+        height: 1.0,
+    };
+
+    fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MotionConfig {
+    /// zones to watch, the whole picture if empty
+    pub zones: Vec<Zone>,
+    /// 1 to 100, a cell changed if its luma differs by more than (100 - sensitivity) % of 255
+    pub sensitivity: u8,
+    /// fraction of a zone which has to change to be motion
+    pub min_area: f32,
+}
+
+/// Luma samples of a raw frame, borrowed from a camera buffer or a synthetic frame.
+pub struct LumaView<'a> {
+    pub data: &'a [u8],
+    pub width: u32,
+    pub height: u32,
+    /// bytes per line
+    pub stride: u32,
+    /// bytes between luma samples of a line, 2 for YUYV and 1 for planar formats
+    pub step: u32,
+}
+
+impl<'a> LumaView<'a> {
+    /// Luma of a YUYV, NV12, YU12 or GREY frame, or None for other formats.
+    pub fn of(
+        data: &'a [u8],
+        width: u32,
+        height: u32,
+        stride: u32,
+        fourcc: &[u8; 4],
+    ) -> Option<Self> {
+        let step = match fourcc {
+            b"YUYV" => 2,
+            b"NV12" | b"YU12" | b"GREY" => 1,
+            _ => return None,
+        };
+        Some(Self {
+            data,
+            width,
+            height,
+            stride,
+            step,
+        })
+    }
+}
+
+/// Cells of the grid luma is averaged into
+const GRID_WIDTH: usize = 64;
+const GRID_HEIGHT: usize = 48;
+/// Frames are analyzed at most this often to save CPU.
+const ANALYSIS_INTERVAL: Duration = Duration::from_millis(200);
+/// Motion is over when no motion is seen for this long.
+const MOTION_HOLD: Duration = Duration::from_secs(2);
+
+pub struct MotionDetector {
+    config: MotionConfig,
+    /// luma difference of a changed cell
+    threshold: u8,
+    grid: Vec<u8>,
+    previous: Option<Vec<u8>>,
+    last_analysis: Option<Instant>,
+    /// when motion was last seen, while motion goes on
+    last_motion: Option<Instant>,
+}
+
+impl MotionDetector {
+    pub fn new(config: MotionConfig) -> Self {
+        let sensitivity = config.sensitivity.clamp(1, 100) as u32;
+        Self {
+            threshold: ((100 - sensitivity) * 255 / 100) as u8,
+            config,
+            grid: vec![0; GRID_WIDTH * GRID_HEIGHT],
+            previous: None,
+            last_analysis: None,
+            last_motion: None,
+        }
+    }
+
+    /// Forgets the previous frame, e.g. when the resolution changes.
+    pub fn reset(&mut self) {
+        self.previous = None;
+    }
+
+    /// Compares a frame captured at `time` with the previous one.
+    pub fn analyze(&mut self, luma: &LumaView, time: Instant) -> Option<MotionEvent> {
+        if self
+            .last_analysis
+            .is_some_and(|last| time.saturating_duration_since(last) < ANALYSIS_INTERVAL)
+        {
+            return None;
+        }
+        self.last_analysis = Some(time);
+
+        if !self.downscale(luma) {
+            return None;
+        }
+        let previous = self.previous.replace(self.grid.clone())?;
+
+        let motion = self.motion_zone(&previous);
+        match (motion, self.last_motion) {
+            (Some(_), Some(_)) => {
+                self.last_motion = Some(time);
+                None
+            }
+            (Some((zone, area)), None) => {
+                self.last_motion = Some(time);
+                Some(MotionEvent::Started { zone, area })
+            }
+            (None, Some(last)) if time.saturating_duration_since(last) >= MOTION_HOLD => {
+                self.last_motion = None;
+                Some(MotionEvent::Stopped)
+            }
+            (None, _) => None,
+        }
+    }
+
+    /// Averages luma into the grid, or returns false if the frame is too small for its size.
+    fn downscale(&mut self, luma: &LumaView) -> bool {
+        let (width, height) = (luma.width as usize, luma.height as usize);
+        let (stride, step) = (luma.stride as usize, luma.step as usize);
+        if width < GRID_WIDTH
+            || height < GRID_HEIGHT
+            || luma.data.len() < stride * (height - 1) + width * step
+        {
+            return false;
+        }
+
+        let (cell_width, cell_height) = (width / GRID_WIDTH, height / GRID_HEIGHT);
+        let cell_pixels = (cell_width * cell_height) as u32;
+        for (grid_y, cells) in self.grid.chunks_exact_mut(GRID_WIDTH).enumerate() {
+            for (grid_x, cell) in cells.iter_mut().enumerate() {
+                let mut sum = 0u32;
+                for y in grid_y * cell_height..(grid_y + 1) * cell_height {
+                    let line = &luma.data[y * stride..];
+                    for x in grid_x * cell_width..(grid_x + 1) * cell_width {
+                        sum += line[x * step] as u32;
+                    }
+                }
+                *cell = (sum / cell_pixels) as u8;
+            }
+        }
+        true
+    }
+
+    /// The first zone with enough changed cells and the changed fraction of its area
+    fn motion_zone(&self, previous: &[u8]) -> Option<(usize, f32)> {
+        let zones: &[Zone] = if self.config.zones.is_empty() {
+            &[Zone::FULL]
+        } else {
+            &self.config.zones
+        };
+        zones.iter().enumerate().find_map(|(index, zone)| {
+            let mut cells = 0;
+            let mut changed = 0;
+            for (i, (&current, &previous)) in self.grid.iter().zip(previous).enumerate() {
+                let x = ((i % GRID_WIDTH) as f32 + 0.5) / GRID_WIDTH as f32;
+                let y = ((i / GRID_WIDTH) as f32 + 0.5) / GRID_HEIGHT as f32;
+                if !zone.contains(x, y) {
+                    continue;
+                }
+                cells += 1;
+                if current.abs_diff(previous) > self.threshold {
+                    changed += 1;
+                }
+            }
+            let area = changed as f32 / cells.max(1) as f32;
+            (cells > 0 && area >= self.config.min_area).then_some((index, area))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 128;
+    const HEIGHT: u32 = 96;
+
+    fn detector(zones: Vec<Zone>, min_area: f32) -> MotionDetector {
+        MotionDetector::new(MotionConfig {
+            zones,
+            sensitivity: 90,
+            min_area,
+        })
+    }
+
+    /// A GREY frame, white in `bright` and black elsewhere
+    fn frame(bright: Option<Zone>) -> Vec<u8> {
+        let mut frame = vec![0; (WIDTH * HEIGHT) as usize];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let (fx, fy) = (
+                    (x as f32 + 0.5) / WIDTH as f32,
+                    (y as f32 + 0.5) / HEIGHT as f32,
+                );
+                if bright.is_some_and(|zone| zone.contains(fx, fy)) {
+                    frame[(y * WIDTH + x) as usize] = 255;
+                }
+            }
+        }
+        frame
+    }
+
+    fn analyze(detector: &mut MotionDetector, frame: &[u8], time: Instant) -> Option<MotionEvent> {
+        let luma = LumaView::of(frame, WIDTH, HEIGHT, WIDTH, b"GREY").unwrap();
+        detector.analyze(&luma, time)
+    }
+
+    const LEFT: Zone = Zone {
+        x: 0.0,
+        y: 0.0,
+        width: 0.5,
+        height: 1.0,
+    };
+    const RIGHT: Zone = Zone {
+        x: 0.5,
+        y: 0.0,
+        width: 0.5,
+        height: 1.0,
+    };
+
+    #[test]
+    fn motion_starts_holds_and_stops() {
+        let mut detector = detector(Vec::new(), 0.01);
+        let (dark, bright) = (frame(None), frame(Some(RIGHT)));
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        assert_eq!(analyze(&mut detector, &dark, at(0)), None);
+        assert!(matches!(
+            analyze(&mut detector, &bright, at(200)),
+            Some(MotionEvent::Started { zone: 0, area }) if (area - 0.5).abs() < 0.01
+        ));
+        // motion goes on
+        assert_eq!(analyze(&mut detector, &dark, at(400)), None);
+        assert_eq!(analyze(&mut detector, &bright, at(600)), None);
+        // still, but held
+        assert_eq!(analyze(&mut detector, &bright, at(800)), None);
+        assert_eq!(analyze(&mut detector, &bright, at(2400)), None);
+        assert_eq!(
+            analyze(&mut detector, &bright, at(2600)),
+            Some(MotionEvent::Stopped)
+        );
+        assert_eq!(analyze(&mut detector, &bright, at(2800)), None);
+    }
+
+    #[test]
+    fn frames_within_the_analysis_interval_are_skipped() {
+        let mut detector = detector(Vec::new(), 0.01);
+        let start = Instant::now();
+        assert_eq!(analyze(&mut detector, &frame(None), start), None);
+        let soon = start + Duration::from_millis(100);
+        assert_eq!(analyze(&mut detector, &frame(Some(RIGHT)), soon), None);
+    }
+
+    #[test]
+    fn motion_is_reported_in_the_changed_zone() {
+        let mut detector = detector(vec![LEFT, RIGHT], 0.5);
+        let start = Instant::now();
+        assert_eq!(analyze(&mut detector, &frame(None), start), None);
+        let event = analyze(
+            &mut detector,
+            &frame(Some(RIGHT)),
+            start + Duration::from_millis(200),
+        );
+        assert!(matches!(event, Some(MotionEvent::Started { zone: 1, area }) if area > 0.99));
+    }
+
+    #[test]
+    fn motion_outside_the_zones_is_ignored() {
+        let mut detector = detector(vec![LEFT], 0.01);
+        let start = Instant::now();
+        assert_eq!(analyze(&mut detector, &frame(None), start), None);
+        let later = start + Duration::from_millis(200);
+        assert_eq!(analyze(&mut detector, &frame(Some(RIGHT)), later), None);
+    }
+
+    #[test]
+    fn motion_needs_the_minimum_area() {
+        let quarter = Zone {
+            x: 0.0,
+            y: 0.0,
+            width: 0.5,
+            height: 0.5,
+        };
+        let mut detector = detector(Vec::new(), 0.3);
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        assert_eq!(analyze(&mut detector, &frame(None), at(0)), None);
+        assert_eq!(analyze(&mut detector, &frame(Some(quarter)), at(200)), None);
+        assert_eq!(analyze(&mut detector, &frame(None), at(400)), None);
+        assert!(matches!(
+            analyze(&mut detector, &frame(Some(RIGHT)), at(600)),
+            Some(MotionEvent::Started { .. })
+        ));
+    }
+
+    #[test]
+    fn reset_forgets_the_previous_frame() {
+        let mut detector = detector(Vec::new(), 0.01);
+        let start = Instant::now();
+        assert_eq!(analyze(&mut detector, &frame(None), start), None);
+        detector.reset();
+        let later = start + Duration::from_millis(200);
+        assert_eq!(analyze(&mut detector, &frame(Some(RIGHT)), later), None);
+        let even_later = start + Duration::from_millis(400);
+        assert_eq!(
+            analyze(&mut detector, &frame(None), even_later),
+            Some(MotionEvent::Started { zone: 0, area: 0.5 })
+        );
+    }
+
+    #[test]
+    fn unsupported_formats_have_no_luma_view() {
+        assert!(LumaView::of(&[], WIDTH, HEIGHT, WIDTH, b"MJPG").is_none());
+    }
+}
@@ -54,6 +54,31 @@ impl<'a> H264Parser<'a> {
     }
 }
 
+/// Types of NAL units this program looks at (ITU-T H.264 Table 7-1).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NalUnitType {
+    /// coded slice of an IDR picture, which starts a GOP
+    Idr,
+    Sps,
+    Pps,
+    AccessUnitDelimiter,
+    Other(u8),
+}
+
+impl NalUnitType {
+    /// The type in the header of a NAL from [H264Parser], if it isn't empty.
+    pub fn of(nal: &[u8]) -> Option<Self> {
+        let header = nal.first()?;
+        Some(match header & 0x1F {
+            5 => Self::Idr,
+            7 => Self::Sps,
+            8 => Self::Pps,
+            9 => Self::AccessUnitDelimiter,
+            x => Self::Other(x),
+        })
+    }
+}
+
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum H264ParserError {
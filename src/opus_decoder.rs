@@ -0,0 +1,140 @@
+use crate::audio::{opus_channels, Error, OPUS_SAMPLE_RATE};
+use crate::resampler::Resampler;
+use opus::Decoder;
+use std::time::Duration;
+
+/// Decodes Opus packets into PCM of any sample rate.
+pub struct PacketDecoder {
+    opus_decoder: Decoder,
+    /// decoded samples at 48 kHz
+    output_buffer: Vec<i16>,
+    resampler: Resampler,
+    /// samples resampled to the output rate
+    resampled: Vec<i16>,
+    channels: usize,
+}
+
+impl PacketDecoder {
+    pub fn new(sample_rate: u32, channels: u32) -> Result<Self, Error> {
+        // the decoder down-mixes or duplicates channels if the remote peer sends
+        // a different channel count than we output
+        let opus_decoder = Decoder::new(OPUS_SAMPLE_RATE, opus_channels(channels)?)?;
+        // a packet is up to 120 ms
+        let output_buffer = vec![0i16; OPUS_SAMPLE_RATE as usize * 120 / 1000 * channels as usize];
+
+        Ok(Self {
+            opus_decoder,
+            output_buffer,
+            resampler: Resampler::new(OPUS_SAMPLE_RATE, sample_rate, channels),
+            resampled: Vec::new(),
+            channels: channels as usize,
+        })
+    }
+
+    /// Decodes a packet and returns the duration and the interleaved PCM.
+    pub fn decode(&mut self, encoded: &[u8]) -> Result<(Duration, &[i16]), Error> {
+        let samples = self
+            .opus_decoder
+            .decode(encoded, &mut self.output_buffer, false)?;
+        let duration = Duration::from_secs(samples as u64) / OPUS_SAMPLE_RATE;
+        Ok((duration, self.resample(samples)))
+    }
+
+    /// Generates PCM for the lost packet of `duration` with the decoder's packet loss concealment.
+    pub fn conceal(&mut self, duration: Duration) -> Result<&[i16], Error> {
+        let len = self.frame_len(duration);
+        let samples = self
+            .opus_decoder
+            .decode(&[], &mut self.output_buffer[..len], false)?;
+        Ok(self.resample(samples))
+    }
+
+    /// Decodes PCM for the lost packet of `duration` from in-band FEC data in the next packet.
+    pub fn recover(&mut self, next: &[u8], duration: Duration) -> Result<&[i16], Error> {
+        let len = self.frame_len(duration);
+        let samples = self
+            .opus_decoder
+            .decode(next, &mut self.output_buffer[..len], true)?;
+        Ok(self.resample(samples))
+    }
+
+    /// The length of the output buffer to decode a frame of `duration`
+    fn frame_len(&self, duration: Duration) -> usize {
+        let samples = duration.as_micros() as usize * OPUS_SAMPLE_RATE as usize / 1_000_000;
+        (samples * self.channels).min(self.output_buffer.len())
+    }
+
+    fn resample(&mut self, samples: usize) -> &[i16] {
+        self.resampled.clear();
+        self.resampler.process(
+            &self.output_buffer[..samples * self.channels],
+            &mut self.resampled,
+        );
+        &self.resampled
+    }
+}
+
+/// Whether a packet carries in-band FEC (LBRR) data of the packet before it,
+/// checked like `opus_packet_has_lbrr` of libopus.
+pub fn has_fec(packet: &[u8]) -> bool {
+    let Some(&toc) = packet.first() else {
+        return false;
+    };
+    let config = toc >> 3;
+    // only SILK frames of SILK-only and hybrid packets have LBRR
+    let frame_ms = match config {
+        0..=11 => [10, 20, 40, 60][config as usize % 4],
+        12..=15 => [10, 20][config as usize % 2],
+        _ => return false,
+    };
+    let Some(first) = first_frame_byte(packet) else {
+        return false;
+    };
+    // the flags follow the VAD flags of the SILK frames, one per 20 ms
+    let silk_frames = (frame_ms / 20).max(1);
+    let stereo = toc & 0x4 != 0;
+    (first >> (7 - silk_frames)) & 1 != 0 || (stereo && (first >> (6 - 2 * silk_frames)) & 1 != 0)
+}
+
+/// The first byte of the first frame of a packet, None if the frame is empty
+fn first_frame_byte(packet: &[u8]) -> Option<u8> {
+    // a frame length takes 2 bytes from 252 on
+    let length_size = |x: u8| if x >= 252 { 2 } else { 1 };
+    let start = match packet[0] & 0x3 {
+        // one frame, or two of the same size
+        0 | 1 => 1,
+        // two frames, the length of the first one is given
+        2 => {
+            let length = *packet.get(1)?;
+            if length == 0 {
+                return None;
+            }
+            1 + length_size(length)
+        }
+        // any number of frames, with padding and lengths if VBR
+        _ => {
+            let count = *packet.get(1)?;
+            let mut start = 2;
+            if count & 0x40 != 0 {
+                loop {
+                    let padding = *packet.get(start)?;
+                    start += 1;
+                    if padding != 255 {
+                        break;
+                    }
+                }
+            }
+            if count & 0x80 != 0 {
+                let length = *packet.get(start)?;
+                if length == 0 {
+                    return None;
+                }
+                for _ in 1..(count & 0x3f) {
+                    start += length_size(*packet.get(start)?);
+                }
+            }
+            start
+        }
+    };
+    packet.get(start).copied()
+}
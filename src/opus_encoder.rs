@@ -0,0 +1,286 @@
+//! Opus encoder with full control over encoder CTLs.
+//!
+//! The `opus` crate only exposes bitrate, VBR and FEC settings of the encoder,
+//! so this talks to libopus through `audiopus_sys` to configure DTX, complexity
+//! and bandwidth as well.
+
+use crate::audio::{opus_channels, Error, FrameDuration, MAX_PACKET_SIZE, OPUS_SAMPLE_RATE};
+use crate::audio_processing::AudioProcessor;
+use crate::resampler::Resampler;
+use crate::voice_activity::{AudioLevel, VoiceActivityDetector};
+use audiopus_sys as ffi;
+use opus::Repacketizer;
+use std::ffi::CStr;
+
+/// The coding mode of the encoder.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OpusApplication {
+    /// Best quality for voice signals
+    Voip,
+    /// Best quality for non-voice signals like music
+    Audio,
+    /// Lowest achievable latency, voice-optimized modes are disabled
+    LowDelay,
+}
+
+impl OpusApplication {
+    fn raw(self) -> i32 {
+        match self {
+            OpusApplication::Voip => ffi::OPUS_APPLICATION_VOIP,
+            OpusApplication::Audio => ffi::OPUS_APPLICATION_AUDIO,
+            OpusApplication::LowDelay => ffi::OPUS_APPLICATION_RESTRICTED_LOWDELAY,
+        }
+    }
+}
+
+/// The audio bandwidth the encoder is allowed to use.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OpusBandwidth {
+    /// 4 kHz passband
+    Narrowband,
+    /// 6 kHz passband
+    Mediumband,
+    /// 8 kHz passband
+    Wideband,
+    /// 12 kHz passband
+    Superwideband,
+    /// 20 kHz passband
+    Fullband,
+}
+
+impl OpusBandwidth {
+    fn raw(self) -> i32 {
+        match self {
+            OpusBandwidth::Narrowband => ffi::OPUS_BANDWIDTH_NARROWBAND,
+            OpusBandwidth::Mediumband => ffi::OPUS_BANDWIDTH_MEDIUMBAND,
+            OpusBandwidth::Wideband => ffi::OPUS_BANDWIDTH_WIDEBAND,
+            OpusBandwidth::Superwideband => ffi::OPUS_BANDWIDTH_SUPERWIDEBAND,
+            OpusBandwidth::Fullband => ffi::OPUS_BANDWIDTH_FULLBAND,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct OpusEncoderConfig {
+    pub application: OpusApplication,
+    /// target bitrate in bits per second
+    pub bit_rate: i32,
+    pub vbr: bool,
+    /// keeps VBR bitrate close to the target bitrate. ignored in CBR.
+    pub constrained_vbr: bool,
+    pub inband_fec: bool,
+    /// expected packet loss in percent, makes the encoder add more redundancy with FEC
+    pub packet_loss_percent: u8,
+    pub dtx: bool,
+    /// 0 to 10, higher is better quality and more CPU time
+    pub complexity: u8,
+    pub max_bandwidth: Option<OpusBandwidth>,
+}
+
+pub struct OpusEncoder {
+    ptr: *mut ffi::OpusEncoder,
+    channels: u32,
+}
+
+// the encoder state is only accessed through &mut self
+unsafe impl Send for OpusEncoder {}
+
+impl OpusEncoder {
+    pub fn new(sample_rate: u32, channels: u32, config: &OpusEncoderConfig) -> Result<Self, Error> {
+        let mut error = 0;
+        let ptr = unsafe {
+            ffi::opus_encoder_create(
+                sample_rate as i32,
+                channels as i32,
+                config.application.raw(),
+                &mut error,
+            )
+        };
+        if error != ffi::OPUS_OK || ptr.is_null() {
+            return Err(Error::Libopus("opus_encoder_create", error));
+        }
+        let mut encoder = Self { ptr, channels };
+
+        encoder.ctl(ffi::OPUS_SET_BITRATE_REQUEST, config.bit_rate)?;
+        encoder.ctl(ffi::OPUS_SET_VBR_REQUEST, config.vbr as i32)?;
+        encoder.ctl(
+            ffi::OPUS_SET_VBR_CONSTRAINT_REQUEST,
+            config.constrained_vbr as i32,
+        )?;
+        encoder.ctl(ffi::OPUS_SET_INBAND_FEC_REQUEST, config.inband_fec as i32)?;
+        encoder.ctl(
+            ffi::OPUS_SET_PACKET_LOSS_PERC_REQUEST,
+            config.packet_loss_percent as i32,
+        )?;
+        encoder.ctl(ffi::OPUS_SET_DTX_REQUEST, config.dtx as i32)?;
+        encoder.ctl(ffi::OPUS_SET_COMPLEXITY_REQUEST, config.complexity as i32)?;
+        if let Some(bandwidth) = config.max_bandwidth {
+            encoder.ctl(ffi::OPUS_SET_MAX_BANDWIDTH_REQUEST, bandwidth.raw())?;
+        }
+
+        Ok(encoder)
+    }
+
+    /// Encodes one frame of interleaved PCM and returns the length of the packet.
+    ///
+    /// With DTX enabled, packets of one or two bytes mean silence and need not be transmitted.
+    pub fn encode(&mut self, pcm: &[i16], output: &mut [u8]) -> Result<usize, Error> {
+        let frame_size = pcm.len() / self.channels as usize;
+        let result = unsafe {
+            ffi::opus_encode(
+                self.ptr,
+                pcm.as_ptr(),
+                frame_size as i32,
+                output.as_mut_ptr(),
+                output.len() as i32,
+            )
+        };
+        if result < 0 {
+            return Err(Error::Libopus("opus_encode", result));
+        }
+        Ok(result as usize)
+    }
+
+    fn ctl(&mut self, request: i32, value: i32) -> Result<(), Error> {
+        let result = unsafe { ffi::opus_encoder_ctl(self.ptr, request, value) };
+        if result != ffi::OPUS_OK {
+            return Err(Error::Libopus("opus_encoder_ctl", result));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for OpusEncoder {
+    fn drop(&mut self) {
+        unsafe { ffi::opus_encoder_destroy(self.ptr) }
+    }
+}
+
+/// Makes Opus packets of the frame duration from PCM of any sample rate.
+pub struct PacketEncoder {
+    opus_encoder: OpusEncoder,
+    /// combines frames into one packet for packets longer than 60 ms
+    repacketizer: Repacketizer,
+    resampler: Resampler,
+    /// samples resampled to 48 kHz, waiting for a packet to be filled
+    pending: Vec<i16>,
+    /// echo cancellation and other processing applied to pending samples by 10 ms frames
+    audio_processor: Option<AudioProcessor>,
+    /// the number of pending samples already processed
+    processed: usize,
+    /// encoded frames of the packet
+    frame_buffers: Vec<Vec<u8>>,
+    encoded_buffer: Vec<u8>,
+    voice_activity: VoiceActivityDetector,
+    /// the level of the last encoded packet
+    audio_level: AudioLevel,
+    frame_duration: FrameDuration,
+    channels: usize,
+}
+
+impl PacketEncoder {
+    pub fn new(
+        sample_rate: u32,
+        channels: u32,
+        config: &OpusEncoderConfig,
+        frame_duration: FrameDuration,
+    ) -> Result<Self, Error> {
+        opus_channels(channels)?;
+        let (frames, _) = frame_duration.frames();
+        Ok(Self {
+            opus_encoder: OpusEncoder::new(OPUS_SAMPLE_RATE, channels, config)?,
+            repacketizer: Repacketizer::new()?,
+            resampler: Resampler::new(sample_rate, OPUS_SAMPLE_RATE, channels),
+            pending: Vec::new(),
+            audio_processor: None,
+            processed: 0,
+            frame_buffers: vec![vec![0u8; MAX_PACKET_SIZE]; frames],
+            encoded_buffer: vec![0u8; MAX_PACKET_SIZE * frames],
+            voice_activity: VoiceActivityDetector::new(),
+            audio_level: AudioLevel {
+                level: 127,
+                voice: false,
+            },
+            frame_duration,
+            channels: channels as usize,
+        })
+    }
+
+    pub fn frame_duration(&self) -> FrameDuration {
+        self.frame_duration
+    }
+
+    /// Processes the audio with `audio_processor` before encoding.
+    pub fn set_audio_processor(&mut self, audio_processor: AudioProcessor) {
+        self.audio_processor = Some(audio_processor);
+    }
+
+    /// The audio level and voice activity of the last packet from [Self::encode_packet]
+    pub fn audio_level(&self) -> AudioLevel {
+        self.audio_level
+    }
+
+    /// Adds interleaved PCM to be encoded.
+    pub fn push(&mut self, pcm: &[i16]) -> Result<(), Error> {
+        self.resampler.process(pcm, &mut self.pending);
+        match &mut self.audio_processor {
+            None => self.processed = self.pending.len(),
+            Some(processor) => {
+                let frame_len = processor.capture_frame_len();
+                while self.pending.len() - self.processed >= frame_len {
+                    let frame = &mut self.pending[self.processed..self.processed + frame_len];
+                    processor.process_capture_frame(frame)?;
+                    self.processed += frame_len;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn packet_len(&self) -> usize {
+        self.frame_duration.samples(OPUS_SAMPLE_RATE) * self.channels
+    }
+
+    /// Whether we have enough samples for [Self::encode_packet]
+    pub fn is_ready(&self) -> bool {
+        self.processed >= self.packet_len()
+    }
+
+    /// Encodes a packet from pushed samples. [Self::is_ready] must be true.
+    pub fn encode_packet(&mut self) -> Result<&[u8], Error> {
+        let packet_len = self.packet_len();
+        let (frames, frame_duration) = self.frame_duration.frames();
+        let frame_len = frame_duration.samples(OPUS_SAMPLE_RATE) * self.channels;
+        self.audio_level = self
+            .voice_activity
+            .analyze(&self.pending[..packet_len], self.frame_duration.duration());
+        let encoded = if frames == 1 {
+            self.opus_encoder
+                .encode(&self.pending[..frame_len], &mut self.encoded_buffer)?
+        } else {
+            let mut lengths = Vec::with_capacity(frames);
+            let pcm_frames = self.pending[..packet_len].chunks_exact(frame_len);
+            for (pcm, buffer) in pcm_frames.zip(&mut self.frame_buffers) {
+                lengths.push(self.opus_encoder.encode(pcm, buffer)?);
+            }
+            let encoded_frames = self
+                .frame_buffers
+                .iter()
+                .zip(lengths)
+                .map(|(buffer, len)| &buffer[..len])
+                .collect::<Vec<_>>();
+            self.repacketizer
+                .combine(&encoded_frames, &mut self.encoded_buffer)?
+        };
+        self.pending.drain(..packet_len);
+        self.processed -= packet_len;
+
+        Ok(&self.encoded_buffer[..encoded])
+    }
+}
+
+pub(crate) fn strerror(code: i32) -> &'static str {
+    unsafe { CStr::from_ptr(ffi::opus_strerror(code)) }
+        .to_str()
+        .unwrap_or("unknown error")
+}
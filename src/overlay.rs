@@ -0,0 +1,448 @@
+//! Text and shapes burned into frames for the encoders and snapshots, like the wall-clock
+//! time and a camera label.
+//!
+//! Text is drawn with an 8x8 bitmap font in white, optionally on a black box,
+//! into the luma of YUYV or NV12 frames and made neutral in chroma.
+//! Shapes are white outlines as thick as a font pixel.
+
+use crate::scaler::FrameLayout;
+use chrono::format::{Item, StrftimeItems};
+use chrono::Local;
+use font8x8::{UnicodeFonts, BASIC_FONTS};
+
+/// Point of the picture a text is aligned to
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+/// Where a text is: at an anchor, moved by an offset.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Position {
+    pub anchor: Anchor,
+    /// pixels to the right and down at the full size
+    pub offset: (i32, i32),
+}
+
+impl Position {
+    /// Parses `ANCHOR[@X,Y]` like `bottom-right@-16,-8`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (anchor, offset) = match value.split_once('@') {
+            Some((anchor, offset)) => {
+                let (x, y) = offset
+                    .split_once(',')
+                    .ok_or_else(|| format!("expected ANCHOR@X,Y: {value}"))?;
+                let x = x.trim().parse::<i32>().map_err(|e| e.to_string())?;
+                let y = y.trim().parse::<i32>().map_err(|e| e.to_string())?;
+                (anchor, (x, y))
+            }
+            None => (value, (0, 0)),
+        };
+        let anchor = <Anchor as clap::ValueEnum>::from_str(anchor, true)?;
+        Ok(Self { anchor, offset })
+    }
+}
+
+/// A text at a position of the picture.
+#[derive(Clone, Debug)]
+pub struct OverlayText {
+    pub position: Position,
+    /// strftime format of the local time, where `{camera}` is the name of the camera
+    pub format: String,
+}
+
+impl OverlayText {
+    /// Parses `POSITION:FORMAT` like `top-left:%F %T`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (position, format) = value
+            .split_once(':')
+            .ok_or_else(|| format!("expected POSITION:TEXT: {value}"))?;
+        let position = Position::parse(position)?;
+        // formatting panics on an invalid format
+        if StrftimeItems::new(format).any(|x| matches!(x, Item::Error)) {
+            return Err(format!("invalid time format: {format}"));
+        }
+        Ok(Self {
+            position,
+            format: format.to_owned(),
+        })
+    }
+}
+
+/// A shape with coordinates in fractions of the width and height of the picture.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OverlayShape {
+    /// outline of a rectangle
+    Rectangle {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    },
+    Line {
+        x0: f32,
+        y0: f32,
+        x1: f32,
+        y1: f32,
+    },
+}
+
+impl OverlayShape {
+    /// Parses `rectangle:X,Y,WIDTH,HEIGHT` or `line:X0,Y0,X1,Y1`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (kind, coordinates) = value
+            .split_once(':')
+            .ok_or_else(|| format!("expected SHAPE:COORDINATES: {value}"))?;
+        let coordinates = coordinates
+            .split(',')
+            .map(|x| x.trim().parse::<f32>().map_err(|e| e.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let [a, b, c, d] = coordinates[..] else {
+            return Err(format!("expected 4 coordinates: {value}"));
+        };
+        if !coordinates.iter().all(|x| (0.0..=1.0).contains(x)) {
+            return Err(format!("coordinates are not within 0..1: {value}"));
+        }
+        match kind {
+            "rectangle" => Ok(OverlayShape::Rectangle {
+                x: a,
+                y: b,
+                width: c,
+                height: d,
+            }),
+            "line" => Ok(OverlayShape::Line {
+                x0: a,
+                y0: b,
+                x1: c,
+                y1: d,
+            }),
+            _ => Err(format!("expected rectangle or line: {kind}")),
+        }
+    }
+}
+
+const WHITE: u8 = 235;
+const BLACK: u8 = 16;
+const NEUTRAL_CHROMA: u8 = 128;
+const GLYPH_SIZE: u32 = 8;
+
+pub struct Overlay {
+    texts: Vec<OverlayText>,
+    shapes: Vec<OverlayShape>,
+    camera_name: String,
+    /// pixels per font pixel at the full size
+    scale: u32,
+    background: bool,
+}
+
+impl Overlay {
+    pub fn new(
+        texts: Vec<OverlayText>,
+        shapes: Vec<OverlayShape>,
+        camera_name: &str,
+        scale: u32,
+        background: bool,
+    ) -> Self {
+        Self {
+            texts,
+            shapes,
+            camera_name: camera_name.to_owned(),
+            scale: scale.max(1),
+            background,
+        }
+    }
+
+    /// The texts to draw now.
+    pub fn render(&self) -> Vec<(Position, String)> {
+        let now = Local::now();
+        self.texts
+            .iter()
+            .map(|text| {
+                let rendered = now
+                    .format(&text.format)
+                    .to_string()
+                    .replace("{camera}", &self.camera_name);
+                (text.position, rendered)
+            })
+            .collect()
+    }
+
+    /// Draws the shapes and rendered texts into a frame scaled down by `divisor`
+    /// from the full size.
+    pub fn draw(
+        &self,
+        frame: &mut [u8],
+        layout: &FrameLayout,
+        texts: &[(Position, String)],
+        divisor: u32,
+    ) {
+        if frame.len() < layout.size() || !matches!(&layout.fourcc, b"YUYV" | b"NV12") {
+            return;
+        }
+        let scale = (self.scale / divisor).max(1);
+        for shape in &self.shapes {
+            draw_shape(frame, layout, shape, scale);
+        }
+        let margin = (GLYPH_SIZE * scale / 2) as i64;
+        for (position, text) in texts {
+            let width = text.chars().count() as u32 * GLYPH_SIZE * scale;
+            let height = GLYPH_SIZE * scale;
+            // the start of the text along an axis of the picture
+            let start = |size: u32, text_size: u32, alignment: i64, offset: i32| {
+                let start = match alignment {
+                    -1 => margin,
+                    0 => (size as i64 - text_size as i64) / 2,
+                    _ => size as i64 - text_size as i64 - margin,
+                };
+                (start + (offset / divisor as i32) as i64).max(0) as u32
+            };
+            let (horizontal, vertical) = match position.anchor {
+                Anchor::TopLeft => (-1, -1),
+                Anchor::Top => (0, -1),
+                Anchor::TopRight => (1, -1),
+                Anchor::Left => (-1, 0),
+                Anchor::Center => (0, 0),
+                Anchor::Right => (1, 0),
+                Anchor::BottomLeft => (-1, 1),
+                Anchor::Bottom => (0, 1),
+                Anchor::BottomRight => (1, 1),
+            };
+            let x = start(layout.width, width, horizontal, position.offset.0);
+            let y = start(layout.height, height, vertical, position.offset.1);
+
+            if self.background {
+                let padding = scale;
+                for box_y in y.saturating_sub(padding)..y + height + padding {
+                    for box_x in x.saturating_sub(padding)..x + width + padding {
+                        set_pixel(frame, layout, box_x, box_y, BLACK);
+                    }
+                }
+            }
+            for (i, c) in text.chars().enumerate() {
+                let Some(glyph) = BASIC_FONTS.get(c) else {
+                    continue;
+                };
+                let glyph_x = x + i as u32 * GLYPH_SIZE * scale;
+                for (row, bits) in glyph.iter().enumerate() {
+                    for column in 0..GLYPH_SIZE {
+                        // the least significant bit is the leftmost pixel
+                        if bits & (1 << column) == 0 {
+                            continue;
+                        }
+                        for dy in 0..scale {
+                            for dx in 0..scale {
+                                set_pixel(
+                                    frame,
+                                    layout,
+                                    glyph_x + column * scale + dx,
+                                    y + row as u32 * scale + dy,
+                                    WHITE,
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Draws a shape with lines `thickness` pixels thick.
+fn draw_shape(frame: &mut [u8], layout: &FrameLayout, shape: &OverlayShape, thickness: u32) {
+    let to_pixels = |x: f32, y: f32| {
+        (
+            (x * layout.width as f32) as i64,
+            (y * layout.height as f32) as i64,
+        )
+    };
+    let lines = match *shape {
+        OverlayShape::Rectangle {
+            x,
+            y,
+            width,
+            height,
+        } => {
+            let (left, top) = to_pixels(x, y);
+            let (right, bottom) = to_pixels(x + width, y + height);
+            // inside the rectangle
+            let (right, bottom) = (right - thickness as i64, bottom - thickness as i64);
+            vec![
+                ((left, top), (right, top)),
+                ((right, top), (right, bottom)),
+                ((right, bottom), (left, bottom)),
+                ((left, bottom), (left, top)),
+            ]
+        }
+        OverlayShape::Line { x0, y0, x1, y1 } => vec![(to_pixels(x0, y0), to_pixels(x1, y1))],
+    };
+    for ((x0, y0), (x1, y1)) in lines {
+        let steps = (x1 - x0).abs().max((y1 - y0).abs()).max(1);
+        for step in 0..=steps {
+            let x = x0 + (x1 - x0) * step / steps;
+            let y = y0 + (y1 - y0) * step / steps;
+            for dy in 0..thickness as i64 {
+                for dx in 0..thickness as i64 {
+                    let (x, y) = (x + dx, y + dy);
+                    if x >= 0 && y >= 0 {
+                        set_pixel(frame, layout, x as u32, y as u32, WHITE);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sets a pixel to a gray level, ignoring pixels outside the frame.
+fn set_pixel(frame: &mut [u8], layout: &FrameLayout, x: u32, y: u32, luma: u8) {
+    if x >= layout.width || y >= layout.height {
+        return;
+    }
+    frame[layout.luma_index(x, y)] = luma;
+    let (cb, cr) = layout.chroma_indexes(x, y);
+    frame[cb] = NEUTRAL_CHROMA;
+    frame[cr] = NEUTRAL_CHROMA;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 128;
+    const HEIGHT: u32 = 8;
+    const GRAY: u8 = 100;
+    const CHROMA: u8 = 200;
+
+    fn frame(layout: &FrameLayout) -> Vec<u8> {
+        let mut frame = vec![CHROMA; layout.size()];
+        for y in 0..layout.height {
+            for x in 0..layout.width {
+                frame[layout.luma_index(x, y)] = GRAY;
+            }
+        }
+        frame
+    }
+
+    fn layouts() -> [FrameLayout; 2] {
+        [
+            FrameLayout::packed(WIDTH, HEIGHT, *b"YUYV"),
+            FrameLayout::packed(WIDTH, HEIGHT, *b"NV12"),
+        ]
+    }
+
+    fn draw_shapes(shapes: Vec<OverlayShape>, layout: &FrameLayout) -> Vec<u8> {
+        let overlay = Overlay::new(Vec::new(), shapes, "camera", 1, false);
+        let mut frame = frame(layout);
+        overlay.draw(&mut frame, layout, &[], 1);
+        frame
+    }
+
+    /// Checks that the pixels `white` tells are white and neutral, and the others are as they were.
+    fn assert_white(frame: &[u8], layout: &FrameLayout, white: impl Fn(u32, u32) -> bool) {
+        for y in 0..layout.height {
+            for x in 0..layout.width {
+                let luma = frame[layout.luma_index(x, y)];
+                if white(x, y) {
+                    let (cb, cr) = layout.chroma_indexes(x, y);
+                    assert_eq!(luma, WHITE, "{x},{y}");
+                    assert_eq!((frame[cb], frame[cr]), (NEUTRAL_CHROMA, NEUTRAL_CHROMA));
+                } else {
+                    assert_eq!(luma, GRAY, "{x},{y}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parses_shapes() {
+        assert_eq!(
+            OverlayShape::parse("line:0,0.5,1,0.5"),
+            Ok(OverlayShape::Line {
+                x0: 0.0,
+                y0: 0.5,
+                x1: 1.0,
+                y1: 0.5
+            })
+        );
+        assert!(OverlayShape::parse("rectangle:0,0,1").is_err());
+        assert!(OverlayShape::parse("rectangle:0,0,1,1.5").is_err());
+        assert!(OverlayShape::parse("circle:0,0,1,1").is_err());
+    }
+
+    #[test]
+    fn rectangle_outline_at_odd_offsets() {
+        // 3,1 to 10,4 in pixels
+        let rectangle = OverlayShape::Rectangle {
+            x: 3.0 / 128.0,
+            y: 1.0 / 8.0,
+            width: 8.0 / 128.0,
+            height: 4.0 / 8.0,
+        };
+        for layout in layouts() {
+            let frame = draw_shapes(vec![rectangle], &layout);
+            assert_white(&frame, &layout, |x, y| {
+                let (on_x, on_y) = ((3..=10).contains(&x), (1..=4).contains(&y));
+                (on_x && (y == 1 || y == 4)) || (on_y && (x == 3 || x == 10))
+            });
+        }
+    }
+
+    #[test]
+    fn shapes_outside_the_picture_are_clipped() {
+        // 100,2 to 163,5 in pixels, past the right edge
+        let rectangle = OverlayShape::Rectangle {
+            x: 100.0 / 128.0,
+            y: 2.0 / 8.0,
+            width: 0.5,
+            height: 0.5,
+        };
+        // from the top left corner to past the bottom right one
+        let line = OverlayShape::Line {
+            x0: 0.0,
+            y0: 0.0,
+            x1: 1.0,
+            y1: 1.0,
+        };
+        for layout in layouts() {
+            let frame = draw_shapes(vec![rectangle], &layout);
+            assert_white(&frame, &layout, |x, y| {
+                (x >= 100 && (y == 2 || y == 5)) || (x == 100 && (2..=5).contains(&y))
+            });
+            let frame = draw_shapes(vec![line], &layout);
+            assert_eq!(frame[layout.luma_index(0, 0)], WHITE);
+            assert_eq!(frame[layout.luma_index(WIDTH - 16, HEIGHT - 1)], WHITE);
+        }
+    }
+
+    #[test]
+    fn texts_outside_the_picture_are_clipped() {
+        let overlay = Overlay::new(Vec::new(), Vec::new(), "camera", 2, true);
+        let texts = [
+            // past the right edge and above the top one
+            Position {
+                anchor: Anchor::BottomRight,
+                offset: (40, -40),
+            },
+            Position {
+                anchor: Anchor::Center,
+                offset: (i32::MAX, i32::MAX),
+            },
+        ]
+        .map(|position| (position, "ABC".to_owned()));
+        for layout in layouts() {
+            let mut frame = frame(&layout);
+            overlay.draw(&mut frame, &layout, &texts, 1);
+            // the box of the first text from the top to the right edge
+            let luma = frame[layout.luma_index(WIDTH - 1, 0)];
+            assert!(luma == BLACK || luma == WHITE);
+            assert_eq!(frame[layout.luma_index(0, HEIGHT - 1)], GRAY);
+        }
+    }
+}
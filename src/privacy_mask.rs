@@ -0,0 +1,409 @@
+//! Regions of the picture which are blacked out or pixelated in every frame
+//! before it's encoded or taken as a snapshot.
+//!
+//! Masks are in fractions of the picture, so they apply to every size the frames are
+//! scaled to, and are persisted as JSON when they are changed at runtime.
+
+use crate::scaler::FrameLayout;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PrivacyMask {
+    pub shape: Shape,
+    #[serde(default)]
+    pub fill: Fill,
+}
+
+/// Coordinates are fractions of the width and height of the picture.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Shape {
+    Rectangle {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    },
+    /// vertices in order, closed from the last one to the first one
+    Polygon { points: Vec<(f32, f32)> },
+}
+
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fill {
+    #[default]
+    Black,
+    Pixelate,
+}
+
+/// Pixelated blocks are this fraction of the picture width.
+const PIXELATE_BLOCK: f32 = 1.0 / 40.0;
+const BLACK: u8 = 16;
+const NEUTRAL_CHROMA: u8 = 128;
+
+/// Whether frames of `fourcc` can be masked
+pub fn can_mask(fourcc: &[u8; 4]) -> bool {
+    matches!(fourcc, b"YUYV" | b"NV12")
+}
+
+/// Checks that masks are within the picture.
+pub fn validate(masks: &[PrivacyMask]) -> io::Result<()> {
+    for (i, mask) in masks.iter().enumerate() {
+        mask.shape.validate().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Privacy mask {i}: {e}"),
+            )
+        })?;
+    }
+    Ok(())
+}
+
+impl Shape {
+    fn validate(&self) -> Result<(), &'static str> {
+        let in_picture = |x: f32| (0.0..=1.0).contains(&x);
+        match self {
+            Shape::Rectangle {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                // NaN isn't in the range either, edges may be off by rounding
+                if ![*x, *y, *width, *height].into_iter().all(in_picture)
+                    || x + width > 1.0 + f32::EPSILON
+                    || y + height > 1.0 + f32::EPSILON
+                {
+                    return Err("rectangle is not within 0..1");
+                }
+            }
+            Shape::Polygon { points } => {
+                if points.len() < 3 {
+                    return Err("polygon has less than 3 points");
+                }
+                if !points.iter().all(|&(x, y)| in_picture(x) && in_picture(y)) {
+                    return Err("polygon is not within 0..1");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Bounding box as fractions: left, top, right, bottom
+    fn bounds(&self) -> (f32, f32, f32, f32) {
+        match self {
+            Shape::Rectangle {
+                x,
+                y,
+                width,
+                height,
+            } => (*x, *y, x + width, y + height),
+            Shape::Polygon { points } => points.iter().fold(
+                (f32::MAX, f32::MAX, f32::MIN, f32::MIN),
+                |(left, top, right, bottom), &(x, y)| {
+                    (left.min(x), top.min(y), right.max(x), bottom.max(y))
+                },
+            ),
+        }
+    }
+
+    fn contains(&self, x: f32, y: f32) -> bool {
+        match self {
+            Shape::Rectangle { .. } => {
+                let (left, top, right, bottom) = self.bounds();
+                x >= left && x < right && y >= top && y < bottom
+            }
+            Shape::Polygon { points } => {
+                // even-odd rule: a ray to the right crosses the edges an odd number of times
+                let mut inside = false;
+                let mut previous = match points.last() {
+                    Some(&point) => point,
+                    None => return false,
+                };
+                for &point in points {
+                    let ((x0, y0), (x1, y1)) = (previous, point);
+                    if (y0 > y) != (y1 > y) && x < x0 + (y - y0) * (x1 - x0) / (y1 - y0) {
+                        inside = !inside;
+                    }
+                    previous = point;
+                }
+                inside
+            }
+        }
+    }
+}
+
+/// Masks a YUYV or NV12 frame.
+///
+/// Fails if the frame can't be masked, so it isn't sent unmasked.
+pub fn apply(masks: &[PrivacyMask], frame: &mut [u8], layout: &FrameLayout) -> io::Result<()> {
+    if masks.is_empty() {
+        return Ok(());
+    }
+    if !can_mask(&layout.fourcc) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "Privacy mask: can't mask {}",
+                String::from_utf8_lossy(&layout.fourcc)
+            ),
+        ));
+    }
+    if frame.len() < layout.size() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Privacy mask: frame is smaller than its format",
+        ));
+    }
+    let (width, height) = (layout.width as f32, layout.height as f32);
+    for mask in masks {
+        let (left, top, right, bottom) = mask.shape.bounds();
+        let to_pixels =
+            |fraction: f32, size: u32| (fraction * size as f32).clamp(0.0, size as f32) as u32;
+        let (left, right) = (
+            to_pixels(left, layout.width),
+            to_pixels(right, layout.width),
+        );
+        let (top, bottom) = (
+            to_pixels(top, layout.height),
+            to_pixels(bottom, layout.height),
+        );
+        let inside = |x: u32, y: u32| {
+            mask.shape
+                .contains((x as f32 + 0.5) / width, (y as f32 + 0.5) / height)
+        };
+
+        match mask.fill {
+            Fill::Black => {
+                for y in top..bottom {
+                    for x in left..right {
+                        if inside(x, y) {
+                            set_pixel(frame, layout, x, y, BLACK, (NEUTRAL_CHROMA, NEUTRAL_CHROMA));
+                        }
+                    }
+                }
+            }
+            Fill::Pixelate => {
+                let block = ((width * PIXELATE_BLOCK) as u32).max(2);
+                // blocks are aligned to the picture so they don't move with the mask
+                for block_y in (top / block * block..bottom).step_by(block as usize) {
+                    for block_x in (left / block * block..right).step_by(block as usize) {
+                        let ys = block_y..(block_y + block).min(layout.height);
+                        let xs = block_x..(block_x + block).min(layout.width);
+                        let mut sum = 0u32;
+                        for y in ys.clone() {
+                            for x in xs.clone() {
+                                sum += frame[layout.luma_index(x, y)] as u32;
+                            }
+                        }
+                        let luma = (sum / (ys.len() * xs.len()) as u32) as u8;
+                        let (cb, cr) = layout.chroma_indexes(block_x, block_y);
+                        let chroma = (frame[cb], frame[cr]);
+                        for y in ys.clone() {
+                            for x in xs.clone() {
+                                if inside(x, y) {
+                                    set_pixel(frame, layout, x, y, luma, chroma);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn set_pixel(frame: &mut [u8], layout: &FrameLayout, x: u32, y: u32, luma: u8, chroma: (u8, u8)) {
+    frame[layout.luma_index(x, y)] = luma;
+    let (cb, cr) = layout.chroma_indexes(x, y);
+    frame[cb] = chroma.0;
+    frame[cr] = chroma.1;
+}
+
+/// Reads masks saved by [save], none if the file doesn't exist yet.
+pub fn load(path: &Path) -> io::Result<Vec<PrivacyMask>> {
+    let masks = match std::fs::read(path) {
+        Ok(json) => serde_json::from_slice(&json)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    validate(&masks)?;
+    Ok(masks)
+}
+
+pub fn save(path: &Path, masks: &[PrivacyMask]) -> io::Result<()> {
+    let json = serde_json::to_vec_pretty(masks)?;
+    // a crash while writing doesn't lose the previous masks
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, json)?;
+    std::fs::rename(temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 128;
+    const HEIGHT: u32 = 8;
+    const CHROMA: u8 = 200;
+
+    /// A frame where the luma differs between neighbours, with colourful chroma
+    fn frame(layout: &FrameLayout) -> Vec<u8> {
+        let mut frame = vec![CHROMA; layout.size()];
+        for y in 0..layout.height {
+            for x in 0..layout.width {
+                frame[layout.luma_index(x, y)] = (50 + (x * 3 + y * 7) % 150) as u8;
+            }
+        }
+        frame
+    }
+
+    /// A rectangle from `left`, `top` to before `right`, `bottom` in pixels
+    fn rectangle(left: u32, top: u32, right: u32, bottom: u32) -> Shape {
+        Shape::Rectangle {
+            x: left as f32 / WIDTH as f32,
+            y: top as f32 / HEIGHT as f32,
+            width: (right - left) as f32 / WIDTH as f32,
+            height: (bottom - top) as f32 / HEIGHT as f32,
+        }
+    }
+
+    fn mask(shape: Shape, fill: Fill) -> Vec<PrivacyMask> {
+        vec![PrivacyMask { shape, fill }]
+    }
+
+    fn layouts() -> [FrameLayout; 3] {
+        [
+            FrameLayout::packed(WIDTH, HEIGHT, *b"YUYV"),
+            FrameLayout::packed(WIDTH, HEIGHT, *b"NV12"),
+            // with padded lines
+            FrameLayout {
+                stride: WIDTH + 32,
+                ..FrameLayout::packed(WIDTH, HEIGHT, *b"NV12")
+            },
+        ]
+    }
+
+    /// Checks that pixels in the rectangle are black and the others are as they were.
+    fn assert_black(
+        frame: &[u8],
+        original: &[u8],
+        layout: &FrameLayout,
+        (left, top, right, bottom): (u32, u32, u32, u32),
+    ) {
+        for y in 0..layout.height {
+            for x in 0..layout.width {
+                let luma = layout.luma_index(x, y);
+                if (left..right).contains(&x) && (top..bottom).contains(&y) {
+                    let (cb, cr) = layout.chroma_indexes(x, y);
+                    assert_eq!(frame[luma], BLACK, "{x},{y}");
+                    assert_eq!((frame[cb], frame[cr]), (NEUTRAL_CHROMA, NEUTRAL_CHROMA));
+                } else {
+                    assert_eq!(frame[luma], original[luma], "{x},{y}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn black_at_odd_offsets() {
+        for layout in layouts() {
+            let original = frame(&layout);
+            let mut frame = original.clone();
+            let masks = mask(rectangle(3, 1, 10, 6), Fill::Black);
+            apply(&masks, &mut frame, &layout).unwrap();
+            assert_black(&frame, &original, &layout, (3, 1, 10, 6));
+        }
+    }
+
+    #[test]
+    fn black_at_the_edge() {
+        for layout in layouts() {
+            let original = frame(&layout);
+            let mut frame = original.clone();
+            let masks = mask(rectangle(117, 5, WIDTH, HEIGHT), Fill::Black);
+            apply(&masks, &mut frame, &layout).unwrap();
+            assert_black(&frame, &original, &layout, (117, 5, WIDTH, HEIGHT));
+        }
+    }
+
+    #[test]
+    fn pixelates_blocks_of_the_picture() {
+        // blocks of 3 pixels, the last ones cut by the right and bottom edges
+        let block = 3;
+        for layout in layouts() {
+            let original = frame(&layout);
+            let mut frame = original.clone();
+            let area = (5, 1, WIDTH, HEIGHT);
+            let masks = mask(rectangle(area.0, area.1, area.2, area.3), Fill::Pixelate);
+            apply(&masks, &mut frame, &layout).unwrap();
+            for y in 0..HEIGHT {
+                for x in 0..WIDTH {
+                    let luma = layout.luma_index(x, y);
+                    if !((area.0..area.2).contains(&x) && (area.1..area.3).contains(&y)) {
+                        assert_eq!(frame[luma], original[luma], "{x},{y}");
+                        continue;
+                    }
+                    // the average of the whole block, also of its pixels outside the mask
+                    let xs = x / block * block..(x / block * block + block).min(WIDTH);
+                    let ys = y / block * block..(y / block * block + block).min(HEIGHT);
+                    let mut sum = 0;
+                    for y in ys.clone() {
+                        for x in xs.clone() {
+                            sum += original[layout.luma_index(x, y)] as u32;
+                        }
+                    }
+                    let average = (sum / (xs.len() * ys.len()) as u32) as u8;
+                    assert_eq!(frame[luma], average, "{x},{y}");
+                    let (cb, cr) = layout.chroma_indexes(x, y);
+                    assert_eq!((frame[cb], frame[cr]), (CHROMA, CHROMA));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn shapes_outside_the_picture_are_clipped() {
+        let shapes = [
+            Shape::Rectangle {
+                x: 0.9,
+                y: 0.5,
+                width: 0.5,
+                height: 0.9,
+            },
+            Shape::Polygon {
+                points: vec![(-0.5, -0.5), (0.5, -0.5), (-0.5, 1.5)],
+            },
+        ];
+        for layout in layouts() {
+            for shape in &shapes {
+                assert!(shape.validate().is_err());
+                for fill in [Fill::Black, Fill::Pixelate] {
+                    let mut frame = frame(&layout);
+                    apply(&mask(shape.clone(), fill), &mut frame, &layout).unwrap();
+                }
+            }
+            let original = frame(&layout);
+            let mut frame = original.clone();
+            apply(&mask(shapes[0].clone(), Fill::Black), &mut frame, &layout).unwrap();
+            // from 0.9 of the width and half the height to the edges
+            assert_black(&frame, &original, &layout, (115, 4, WIDTH, HEIGHT));
+            let mut frame = original.clone();
+            apply(&mask(shapes[1].clone(), Fill::Black), &mut frame, &layout).unwrap();
+            let corner = layout.luma_index(WIDTH - 1, HEIGHT - 1);
+            assert_eq!(frame[layout.luma_index(0, 0)], BLACK);
+            assert_eq!(frame[corner], original[corner]);
+        }
+    }
+
+    #[test]
+    fn frames_smaller_than_their_format_are_refused() {
+        let layout = FrameLayout::packed(WIDTH, HEIGHT, *b"NV12");
+        let mut frame = vec![0; layout.size() - 1];
+        let masks = mask(rectangle(0, 0, WIDTH, HEIGHT), Fill::Black);
+        assert!(apply(&masks, &mut frame, &layout).is_err());
+    }
+}
@@ -0,0 +1,453 @@
+//! Records streamed media into Matroska files, rotated by size and duration.
+//!
+//! Frames are handed to a thread writing files through a queue bounded for media,
+//! so a slow disk drops frames from the recording rather than stalling the stream.
+//! Events are queued in order with the media but never dropped.
+//!
+//! In triggered mode, the last seconds of media are held in memory starting at a keyframe,
+//! and written out with what follows when an event is triggered, until the event is over.
+
+use crate::matroska::{avc_decoder_configuration, MatroskaWriter, TrackCodec, TrackInfo};
+use crate::nal_parser::{H264Parser, NalUnitType};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc;
+
+#[derive(Clone, Debug)]
+pub struct RecorderConfig {
+    pub directory: PathBuf,
+    /// start a new file at the next keyframe once the file gets this large (bytes)
+    pub max_size: u64,
+    /// start a new file at the next keyframe once the file gets this long
+    pub max_duration: Duration,
+    pub width: u32,
+    pub height: u32,
+    pub audio_channels: u32,
+    /// channels of the remote audio track, recorded if given
+    pub remote_audio_channels: Option<u32>,
+    pub mode: RecordingMode,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum RecordingMode {
+    /// record everything
+    Continuous,
+    /// record only around events passed to [RecorderHandle::trigger]
+    Triggered {
+        /// media kept in memory to be recorded before the event
+        pre_event: Duration,
+        /// how long to keep recording after the last trigger
+        post_event: Duration,
+    },
+}
+
+enum Frame {
+    /// access unit of the camera in Annex-B byte stream format
+    Video(Vec<u8>, Instant),
+    /// Opus packet sent to the remote peer
+    Audio(Vec<u8>, Instant),
+    /// Opus packet received from the remote peer
+    RemoteAudio(Vec<u8>, Instant),
+    /// an event to record with its cause
+    Trigger(String, Instant),
+    /// an event lasting until released with the same cause
+    Hold(String, Instant),
+    Release(String, Instant),
+    /// the camera changed resolution, which goes to the next file
+    Resolution(u32, u32),
+}
+
+impl Frame {
+    fn is_media(&self) -> bool {
+        matches!(
+            self,
+            Frame::Video(..) | Frame::Audio(..) | Frame::RemoteAudio(..)
+        )
+    }
+}
+
+/// Sends frames to the recording thread. The thread ends when all handles are dropped.
+#[derive(Clone)]
+pub struct RecorderHandle {
+    sender: mpsc::UnboundedSender<Frame>,
+    /// media frames in the queue
+    queued: Arc<AtomicUsize>,
+}
+
+/// Media frames waiting to be written, about 2 seconds of video and audio
+const QUEUE_SIZE: usize = 256;
+/// Bytes of media held before an event at most, for encoders with long keyframe intervals
+const MAX_PRE_EVENT_SIZE: usize = 64 << 20;
+
+impl RecorderHandle {
+    /// Starts the recording thread.
+    pub fn spawn(config: RecorderConfig) -> io::Result<Self> {
+        std::fs::create_dir_all(&config.directory)?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let recorder_queued = queued.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = Recorder::new(config).run(receiver, &recorder_queued) {
+                println!("recorder stopped: {e}");
+            }
+        });
+        Ok(Self { sender, queued })
+    }
+
+    pub fn video(&self, access_unit: &[u8]) {
+        self.send(Frame::Video(access_unit.to_vec(), Instant::now()));
+    }
+
+    pub fn audio(&self, packet: &[u8]) {
+        self.send(Frame::Audio(packet.to_vec(), Instant::now()));
+    }
+
+    pub fn remote_audio(&self, packet: &[u8]) {
+        self.send(Frame::RemoteAudio(packet.to_vec(), Instant::now()));
+    }
+
+    /// Records an event in triggered mode. `cause` is only logged.
+    pub fn trigger(&self, cause: &str) {
+        self.send(Frame::Trigger(cause.to_string(), Instant::now()));
+    }
+
+    /// Records an event in triggered mode until [Self::release] with the same `cause`,
+    /// and the post-event time after it, like motion which may last longer than that.
+    pub fn hold(&self, cause: &str) {
+        self.send(Frame::Hold(cause.to_string(), Instant::now()));
+    }
+
+    pub fn release(&self, cause: &str) {
+        self.send(Frame::Release(cause.to_string(), Instant::now()));
+    }
+
+    pub fn resolution(&self, width: u32, height: u32) {
+        self.send(Frame::Resolution(width, height));
+    }
+
+    fn send(&self, frame: Frame) {
+        if frame.is_media() && self.queued.fetch_add(1, Ordering::Relaxed) >= QUEUE_SIZE {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            println!("recorder is behind, dropping a frame");
+            return;
+        }
+        // the recorder may have stopped
+        let _ = self.sender.send(frame);
+    }
+}
+
+const VIDEO_TRACK: u64 = 1;
+const AUDIO_TRACK: u64 = 2;
+const REMOTE_AUDIO_TRACK: u64 = 3;
+
+struct Recorder {
+    config: RecorderConfig,
+    file: Option<RecordingFile>,
+    /// the latest parameter sets from the encoder
+    sps: Vec<u8>,
+    pps: Vec<u8>,
+    /// media before an event in triggered mode, starting with a keyframe
+    pre_event: VecDeque<Sample>,
+    /// bytes of the samples in `pre_event`
+    pre_event_size: usize,
+    /// when the current event ends in triggered mode
+    event_end: Option<Instant>,
+    /// causes of events going on until they are released, the event doesn't end before
+    holds: Vec<String>,
+    /// files opened so far, to tell apart files opened in the same millisecond
+    files: u64,
+}
+
+/// A frame ready to be written to a file
+struct Sample {
+    track: u64,
+    data: Vec<u8>,
+    keyframe: bool,
+    time: Instant,
+}
+
+struct RecordingFile {
+    writer: MatroskaWriter<BufWriter<File>>,
+    start: Instant,
+    /// the parameter sets in the header, a change needs a new file
+    sps: Vec<u8>,
+    pps: Vec<u8>,
+}
+
+impl Recorder {
+    fn new(config: RecorderConfig) -> Self {
+        Self {
+            config,
+            file: None,
+            sps: Vec::new(),
+            pps: Vec::new(),
+            pre_event: VecDeque::new(),
+            pre_event_size: 0,
+            event_end: None,
+            holds: Vec::new(),
+            files: 0,
+        }
+    }
+
+    fn run(
+        mut self,
+        mut receiver: mpsc::UnboundedReceiver<Frame>,
+        queued: &AtomicUsize,
+    ) -> io::Result<()> {
+        while let Some(frame) = receiver.blocking_recv() {
+            if frame.is_media() {
+                queued.fetch_sub(1, Ordering::Relaxed);
+            }
+            let sample = match frame {
+                Frame::Video(access_unit, time) => self.video_sample(&access_unit, time)?,
+                Frame::Audio(data, time) => Sample {
+                    track: AUDIO_TRACK,
+                    data,
+                    keyframe: true,
+                    time,
+                },
+                Frame::RemoteAudio(data, time) => Sample {
+                    track: REMOTE_AUDIO_TRACK,
+                    data,
+                    keyframe: true,
+                    time,
+                },
+                Frame::Trigger(cause, time) => {
+                    self.trigger(&cause, time)?;
+                    continue;
+                }
+                Frame::Hold(cause, time) => {
+                    self.trigger(&cause, time)?;
+                    if self.event_end.is_some() && !self.holds.contains(&cause) {
+                        self.holds.push(cause);
+                    }
+                    continue;
+                }
+                Frame::Release(cause, time) => {
+                    if let Some(index) = self.holds.iter().position(|x| *x == cause) {
+                        self.holds.remove(index);
+                        // the post-event time counts from the release
+                        self.trigger(&cause, time)?;
+                    }
+                    continue;
+                }
+                Frame::Resolution(width, height) => {
+                    // the encoder sends a new SPS, which starts a new file
+                    self.config.width = width;
+                    self.config.height = height;
+                    continue;
+                }
+            };
+            match self.config.mode {
+                RecordingMode::Continuous => self.write(sample)?,
+                RecordingMode::Triggered { pre_event, .. } => match self.event_end {
+                    Some(end) if sample.time <= end || !self.holds.is_empty() => {
+                        self.write(sample)?
+                    }
+                    Some(_) => {
+                        println!("event recording finished");
+                        self.event_end = None;
+                        self.file = None;
+                        self.buffer(sample, pre_event);
+                    }
+                    None => self.buffer(sample, pre_event),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Converts an access unit to the sample stored in Matroska,
+    /// keeping its parameter sets for the file header.
+    fn video_sample(&mut self, access_unit: &[u8], time: Instant) -> io::Result<Sample> {
+        // Matroska stores H.264 as AVC samples: NALs prefixed with 4-byte length
+        // and parameter sets in the codec private data
+        let mut data = Vec::with_capacity(access_unit.len());
+        let mut keyframe = false;
+        let mut nals = H264Parser::new(access_unit);
+        while let Some(nal) = nals
+            .next_buffer()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        {
+            let Some(nal_type) = NalUnitType::of(nal) else {
+                continue;
+            };
+            match nal_type {
+                NalUnitType::Idr => keyframe = true,
+                NalUnitType::Sps => self.sps = nal.to_vec(),
+                NalUnitType::Pps => self.pps = nal.to_vec(),
+                _ => {}
+            }
+            // parameter sets go to the header and access unit delimiters aren't needed
+            if matches!(
+                nal_type,
+                NalUnitType::Sps | NalUnitType::Pps | NalUnitType::AccessUnitDelimiter
+            ) {
+                continue;
+            }
+            data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            data.extend_from_slice(nal);
+        }
+        Ok(Sample {
+            track: VIDEO_TRACK,
+            data,
+            keyframe,
+            time,
+        })
+    }
+
+    fn write(&mut self, sample: Sample) -> io::Result<()> {
+        let is_video = sample.track == VIDEO_TRACK;
+        if is_video && sample.keyframe && self.should_rotate(sample.time) {
+            self.open_file(sample.time)?;
+        }
+        // the file starts with a keyframe
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        let timestamp = sample
+            .time
+            .saturating_duration_since(file.start)
+            .as_millis() as u64;
+        file.writer
+            .write_frame(sample.track, timestamp, sample.keyframe, &sample.data)
+    }
+
+    /// Keeps a sample for an event which may come, dropping GOPs older than `pre_event`
+    /// and the oldest GOPs beyond [MAX_PRE_EVENT_SIZE].
+    fn buffer(&mut self, sample: Sample, pre_event: Duration) {
+        if sample.track == VIDEO_TRACK && sample.keyframe {
+            // keep the latest GOP which started at least `pre_event` ago
+            // so that the whole `pre_event` is covered
+            let start = sample.time.checked_sub(pre_event);
+            let first_kept = self.pre_event.iter().rposition(|x| {
+                x.track == VIDEO_TRACK && x.keyframe && start.is_some_and(|start| x.time <= start)
+            });
+            if let Some(first_kept) = first_kept {
+                self.drop_pre_event(first_kept);
+            }
+        } else if self.pre_event.is_empty() {
+            // the buffer starts with a keyframe
+            return;
+        }
+        self.pre_event_size += sample.data.len();
+        self.pre_event.push_back(sample);
+
+        while self.pre_event_size > MAX_PRE_EVENT_SIZE {
+            // keep starting with a keyframe, or start over at the next one
+            let next_keyframe = self
+                .pre_event
+                .iter()
+                .skip(1)
+                .position(|x| x.track == VIDEO_TRACK && x.keyframe)
+                .map_or(self.pre_event.len(), |i| i + 1);
+            self.drop_pre_event(next_keyframe);
+        }
+    }
+
+    /// Drops the first `count` samples held before an event.
+    fn drop_pre_event(&mut self, count: usize) {
+        for sample in self.pre_event.drain(..count) {
+            self.pre_event_size -= sample.data.len();
+        }
+    }
+
+    /// Starts or extends an event, writing the media buffered before it first.
+    fn trigger(&mut self, cause: &str, time: Instant) -> io::Result<()> {
+        let RecordingMode::Triggered { post_event, .. } = self.config.mode else {
+            println!("event triggered by {cause}, already recording continuously");
+            return Ok(());
+        };
+        println!("event triggered by {cause}");
+        let end = time + post_event;
+        if self
+            .event_end
+            .replace(end.max(self.event_end.unwrap_or(end)))
+            .is_none()
+        {
+            self.pre_event_size = 0;
+            while let Some(sample) = self.pre_event.pop_front() {
+                self.write(sample)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether to start a new file at this keyframe
+    fn should_rotate(&self, time: Instant) -> bool {
+        if self.sps.len() < 4 || self.pps.is_empty() {
+            // we can't write the header yet
+            return false;
+        }
+        match &self.file {
+            None => true,
+            Some(file) => {
+                file.writer.written() >= self.config.max_size
+                    || time.saturating_duration_since(file.start) >= self.config.max_duration
+                    || file.sps != self.sps
+                    || file.pps != self.pps
+            }
+        }
+    }
+
+    fn open_file(&mut self, time: Instant) -> io::Result<()> {
+        // finish the previous file first
+        self.file = None;
+
+        let millis = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |x| x.as_millis());
+        // never overwrite a recording, e.g. of a previous run in the same millisecond
+        let (path, file) = loop {
+            self.files += 1;
+            let path = self
+                .config
+                .directory
+                .join(format!("{millis}-{}.mkv", self.files));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => break (path, file),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        println!("recording to {}", path.display());
+
+        let mut tracks = vec![
+            TrackInfo {
+                name: "camera",
+                codec: TrackCodec::H264 {
+                    avc_config: avc_decoder_configuration(&self.sps, &self.pps),
+                    width: self.config.width,
+                    height: self.config.height,
+                },
+            },
+            TrackInfo {
+                name: "microphone",
+                codec: TrackCodec::Opus {
+                    channels: self.config.audio_channels,
+                },
+            },
+        ];
+        if let Some(channels) = self.config.remote_audio_channels {
+            tracks.push(TrackInfo {
+                name: "remote",
+                codec: TrackCodec::Opus { channels },
+            });
+        }
+
+        let writer = MatroskaWriter::new(BufWriter::new(file), &tracks)?;
+        self.file = Some(RecordingFile {
+            writer,
+            start: time,
+            sps: self.sps.clone(),
+            pps: self.pps.clone(),
+        });
+        Ok(())
+    }
+}
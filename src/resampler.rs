@@ -0,0 +1,62 @@
+/// Streaming sample-rate converter for interleaved PCM with linear interpolation.
+///
+/// This is cheap enough for the Pi but does not filter aliasing on down sampling,
+/// which is acceptable for voice going through Opus.
+pub struct Resampler {
+    channels: usize,
+    /// input frames per output frame
+    step: f64,
+    /// position of the next output frame in input frames, relative to `last`
+    position: f64,
+    /// the last input frame of the previous call, interpolated with the first frame of the next call
+    last: Vec<i16>,
+    passthrough: bool,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32, channels: u32) -> Self {
+        Self {
+            channels: channels as usize,
+            step: from_rate as f64 / to_rate as f64,
+            position: 1.0,
+            last: vec![0; channels as usize],
+            passthrough: from_rate == to_rate,
+        }
+    }
+
+    /// Converts `input` and appends the result to `output`.
+    pub fn process(&mut self, input: &[i16], output: &mut Vec<i16>) {
+        if self.passthrough {
+            output.extend_from_slice(input);
+            return;
+        }
+
+        let channels = self.channels;
+        let frames = input.len() / channels;
+        let last = &self.last;
+        // index 0 is `last` and index n is input[n - 1]
+        let sample = |index: usize, channel: usize| match index {
+            0 => last[channel],
+            _ => input[(index - 1) * channels + channel],
+        };
+
+        let mut position = self.position;
+        while position < frames as f64 {
+            let index = position.floor();
+            let fraction = position - index;
+            let index = index as usize;
+            for channel in 0..channels {
+                let a = sample(index, channel) as f64;
+                let b = sample(index + 1, channel) as f64;
+                output.push((a + (b - a) * fraction).round() as i16);
+            }
+            position += self.step;
+        }
+
+        self.position = position - frames as f64;
+        if frames != 0 {
+            self.last
+                .copy_from_slice(&input[(frames - 1) * channels..frames * channels]);
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! Lowers the camera resolution while the viewer reports packet loss
+//! and raises it back once the loss is gone.
+
+use std::time::{Duration, Instant};
+
+/// A report with more loss than this is lossy.
+const LOSSY_FRACTION: f32 = 0.1;
+/// A report with less loss than this is clean.
+const CLEAN_FRACTION: f32 = 0.02;
+/// Step down after this many lossy reports in a row.
+const LOSSY_REPORTS: u32 = 3;
+/// Step up after clean reports for this long.
+const CLEAN_DURATION: Duration = Duration::from_secs(10);
+/// Steps aren't smaller than this width.
+const MIN_WIDTH: u32 = 160;
+/// Steps are about these fractions of the configured resolution.
+const STEPS: [(u32, u32); 3] = [(3, 4), (1, 2), (1, 4)];
+
+pub struct ResolutionAdapter {
+    /// resolutions from the configured one down
+    ladder: Vec<(u32, u32)>,
+    step: usize,
+    lossy_reports: u32,
+    /// since when all reports were clean
+    clean_since: Option<Instant>,
+}
+
+impl ResolutionAdapter {
+    /// Steps to any size, for frames which are scaled to the streaming size.
+    pub fn new(width: u32, height: u32) -> Self {
+        let mut ladder = vec![(width, height)];
+        for (numerator, denominator) in STEPS {
+            // encoders want sizes aligned to macroblocks
+            let step = (
+                width * numerator / denominator / 16 * 16,
+                height * numerator / denominator / 16 * 16,
+            );
+            if step.0 >= MIN_WIDTH && step.1 > 0 {
+                ladder.push(step);
+            }
+        }
+        Self::with_ladder(ladder)
+    }
+
+    /// Steps to the `sizes` a camera captures at which have the aspect ratio of
+    /// the configured resolution, for frames which are streamed as captured.
+    pub fn with_sizes(width: u32, height: u32, sizes: &[(u32, u32)]) -> Self {
+        let mut ladder = vec![(width, height)];
+        for (numerator, denominator) in STEPS {
+            let previous = ladder[ladder.len() - 1].0;
+            let target = width * numerator / denominator;
+            let step = sizes
+                .iter()
+                .copied()
+                .filter(|&(step_width, step_height)| {
+                    step_width <= target
+                        && step_width < previous
+                        && step_width >= MIN_WIDTH
+                        && same_aspect((step_width, step_height), (width, height))
+                })
+                .max_by_key(|&(step_width, _)| step_width);
+            if let Some(step) = step {
+                ladder.push(step);
+            }
+        }
+        Self::with_ladder(ladder)
+    }
+
+    fn with_ladder(ladder: Vec<(u32, u32)>) -> Self {
+        Self {
+            ladder,
+            step: 0,
+            lossy_reports: 0,
+            clean_since: None,
+        }
+    }
+
+    /// Takes the fraction of packets lost from a receiver report,
+    /// returning the resolution to switch to if it should change.
+    pub fn on_report(&mut self, fraction_lost: f32, now: Instant) -> Option<(u32, u32)> {
+        if fraction_lost > LOSSY_FRACTION {
+            self.clean_since = None;
+            self.lossy_reports += 1;
+            if self.lossy_reports >= LOSSY_REPORTS && self.step + 1 < self.ladder.len() {
+                self.lossy_reports = 0;
+                self.step += 1;
+                return Some(self.ladder[self.step]);
+            }
+        } else if fraction_lost < CLEAN_FRACTION {
+            self.lossy_reports = 0;
+            let clean_since = *self.clean_since.get_or_insert(now);
+            if self.step > 0 && now.saturating_duration_since(clean_since) >= CLEAN_DURATION {
+                self.clean_since = Some(now);
+                self.step -= 1;
+                return Some(self.ladder[self.step]);
+            }
+        } else {
+            self.lossy_reports = 0;
+        }
+        None
+    }
+}
+
+/// Whether the aspect ratios are within 1%, like the one of 848x480 and 16:9
+fn same_aspect((width0, height0): (u32, u32), (width1, height1): (u32, u32)) -> bool {
+    let (a, b) = (
+        width0 as u64 * height1 as u64,
+        height0 as u64 * width1 as u64,
+    );
+    a.abs_diff(b) * 100 <= a.max(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOSSY: f32 = 0.2;
+    const CLEAN: f32 = 0.0;
+
+    #[test]
+    fn ladder_is_aligned_and_bounded() {
+        let adapter = ResolutionAdapter::new(1280, 720);
+        assert_eq!(
+            adapter.ladder,
+            vec![(1280, 720), (960, 528), (640, 352), (320, 176)]
+        );
+        // a quarter of 480 is narrower than MIN_WIDTH
+        let adapter = ResolutionAdapter::new(480, 360);
+        assert_eq!(adapter.ladder, vec![(480, 360), (352, 256), (240, 176)]);
+    }
+
+    #[test]
+    fn ladder_takes_camera_sizes_of_the_aspect_ratio() {
+        let sizes = [
+            (1280, 720),
+            (1024, 768),
+            (960, 540),
+            (848, 480),
+            (640, 480),
+            (640, 360),
+            (424, 240),
+            (320, 240),
+            (320, 180),
+            (160, 90),
+        ];
+        let adapter = ResolutionAdapter::with_sizes(1280, 720, &sizes);
+        assert_eq!(
+            adapter.ladder,
+            vec![(1280, 720), (960, 540), (640, 360), (320, 180)]
+        );
+        // each step is smaller than the one before, however far apart the sizes are
+        let adapter = ResolutionAdapter::with_sizes(640, 480, &sizes);
+        assert_eq!(adapter.ladder, vec![(640, 480), (320, 240)]);
+        let adapter = ResolutionAdapter::with_sizes(640, 480, &[(640, 480), (480, 360)]);
+        assert_eq!(adapter.ladder, vec![(640, 480), (480, 360)]);
+    }
+
+    #[test]
+    fn ladder_without_camera_sizes_stays() {
+        let mut adapter = ResolutionAdapter::with_sizes(1280, 720, &[(1280, 720), (640, 480)]);
+        assert_eq!(adapter.ladder, vec![(1280, 720)]);
+        let now = Instant::now();
+        for _ in 0..LOSSY_REPORTS * 2 {
+            assert_eq!(adapter.on_report(LOSSY, now), None);
+        }
+    }
+
+    #[test]
+    fn steps_down_after_lossy_reports() {
+        let mut adapter = ResolutionAdapter::new(1280, 720);
+        let now = Instant::now();
+        for _ in 1..LOSSY_REPORTS {
+            assert_eq!(adapter.on_report(LOSSY, now), None);
+        }
+        assert_eq!(adapter.on_report(LOSSY, now), Some((960, 528)));
+        // counting starts over at the new step
+        for _ in 1..LOSSY_REPORTS {
+            assert_eq!(adapter.on_report(LOSSY, now), None);
+        }
+        assert_eq!(adapter.on_report(LOSSY, now), Some((640, 352)));
+    }
+
+    #[test]
+    fn lossy_reports_must_be_in_a_row() {
+        let mut adapter = ResolutionAdapter::new(1280, 720);
+        let now = Instant::now();
+        for _ in 0..10 {
+            for _ in 1..LOSSY_REPORTS {
+                assert_eq!(adapter.on_report(LOSSY, now), None);
+            }
+            // between clean and lossy
+            assert_eq!(adapter.on_report(0.05, now), None);
+        }
+    }
+
+    #[test]
+    fn stays_at_the_lowest_step() {
+        let mut adapter = ResolutionAdapter::new(1280, 720);
+        let now = Instant::now();
+        let steps: Vec<_> = (0..LOSSY_REPORTS * 10)
+            .filter_map(|_| adapter.on_report(LOSSY, now))
+            .collect();
+        assert_eq!(steps, vec![(960, 528), (640, 352), (320, 176)]);
+    }
+
+    #[test]
+    fn steps_up_after_clean_duration() {
+        let mut adapter = ResolutionAdapter::new(1280, 720);
+        let start = Instant::now();
+        for _ in 0..LOSSY_REPORTS * 2 {
+            adapter.on_report(LOSSY, start);
+        }
+        assert_eq!(adapter.step, 2);
+
+        let second = Duration::from_secs(1);
+        assert_eq!(adapter.on_report(CLEAN, start), None);
+        assert_eq!(
+            adapter.on_report(CLEAN, start + CLEAN_DURATION - second),
+            None
+        );
+        assert_eq!(
+            adapter.on_report(CLEAN, start + CLEAN_DURATION),
+            Some((960, 528))
+        );
+        // the next step up needs another clean duration
+        assert_eq!(
+            adapter.on_report(CLEAN, start + CLEAN_DURATION + second),
+            None
+        );
+        assert_eq!(
+            adapter.on_report(CLEAN, start + CLEAN_DURATION * 2),
+            Some((1280, 720))
+        );
+        // and it doesn't go above the configured resolution
+        assert_eq!(adapter.on_report(CLEAN, start + CLEAN_DURATION * 4), None);
+    }
+
+    #[test]
+    fn lossy_report_restarts_clean_duration() {
+        let mut adapter = ResolutionAdapter::new(1280, 720);
+        let start = Instant::now();
+        for _ in 0..LOSSY_REPORTS {
+            adapter.on_report(LOSSY, start);
+        }
+        adapter.on_report(CLEAN, start);
+        let half = CLEAN_DURATION / 2;
+        adapter.on_report(LOSSY, start + half);
+        adapter.on_report(CLEAN, start + half);
+        assert_eq!(adapter.on_report(CLEAN, start + CLEAN_DURATION), None);
+        assert_eq!(
+            adapter.on_report(CLEAN, start + half + CLEAN_DURATION),
+            Some((1280, 720))
+        );
+    }
+}
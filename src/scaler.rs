@@ -0,0 +1,319 @@
+//! Scaling of raw camera frames, for streaming at another size than captured
+//! and for the lower simulcast layers.
+//!
+//! A V4L2 M2M scaler like the ISP of a Pi can also convert the pixel format.
+//! Software scaling picks pixels rather than averaging them, which is cheap
+//! enough to run on every frame of a Pi, but keeps the pixel format.
+
+use std::io;
+use std::sync::Arc;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use v4l::buffer::Type;
+use v4l::capability::Flags;
+use v4l::device::MultiPlaneDevice;
+use v4l::format::MultiPlaneFormat;
+use v4l::io::traits::{CaptureStream, OutputStream, Stream};
+use v4l::prelude::*;
+use v4l::video::{Capture, Output};
+use v4l::FourCC;
+
+/// The size of a frame scaled down by `divisor`, aligned to macroblocks for the encoders
+/// like the steps of [crate::resolution_adaptation]. Frames aren't scaled by 1.
+pub fn scaled_size(width: u32, height: u32, divisor: u32) -> (u32, u32) {
+    if divisor == 1 {
+        return (width, height);
+    }
+    (width / divisor / 16 * 16, height / divisor / 16 * 16)
+}
+
+/// Layout of a raw frame
+#[derive(Copy, Clone)]
+pub struct FrameLayout {
+    pub width: u32,
+    pub height: u32,
+    /// bytes per line of the (first) plane
+    pub stride: u32,
+    pub fourcc: [u8; 4],
+}
+
+impl FrameLayout {
+    /// A frame without line padding
+    pub fn packed(width: u32, height: u32, fourcc: [u8; 4]) -> Self {
+        let stride = match &fourcc {
+            b"YUYV" | b"UYVY" => width * 2,
+            _ => width,
+        };
+        Self {
+            width,
+            height,
+            stride,
+            fourcc,
+        }
+    }
+
+    /// Index of the luma of a pixel in a YUYV or NV12 frame
+    pub fn luma_index(&self, x: u32, y: u32) -> usize {
+        let (x, y, stride) = (x as usize, y as usize, self.stride as usize);
+        match &self.fourcc {
+            b"YUYV" => y * stride + x * 2,
+            _ => y * stride + x,
+        }
+    }
+
+    /// Indexes of Cb and Cr of a pixel in a YUYV or NV12 frame, shared with its neighbours
+    pub fn chroma_indexes(&self, x: u32, y: u32) -> (usize, usize) {
+        let (x, y, stride) = (x as usize & !1, y as usize, self.stride as usize);
+        match &self.fourcc {
+            b"YUYV" => {
+                let pair = y * stride + x * 2;
+                (pair + 1, pair + 3)
+            }
+            _ => {
+                let pair = stride * self.height as usize + y / 2 * stride + x;
+                (pair, pair + 1)
+            }
+        }
+    }
+
+    /// Bytes of a frame
+    pub fn size(&self) -> usize {
+        let lines = match &self.fourcc {
+            b"NV12" | b"NV21" | b"YU12" | b"YV12" => self.height * 3 / 2,
+            _ => self.height,
+        };
+        (self.stride * lines) as usize
+    }
+}
+
+/// Scales a YUYV or NV12 frame to `width`x`height` into `scaled` without line padding,
+/// returning the bytes written.
+pub fn scale(
+    frame: &[u8],
+    layout: &FrameLayout,
+    width: u32,
+    height: u32,
+    scaled: &mut [u8],
+) -> io::Result<usize> {
+    let (width, height) = ((width & !1) as usize, (height & !1) as usize);
+    let (source_width, source_height) = (layout.width as usize, layout.height as usize);
+    let stride = layout.stride as usize;
+    let (lines, len) = match &layout.fourcc {
+        b"YUYV" => (source_height, width * height * 2),
+        b"NV12" => (source_height * 3 / 2, width * height * 3 / 2),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Scaler: only YUYV and NV12 are supported in software",
+            ))
+        }
+    };
+    if frame.len() < stride * lines || scaled.len() < len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Scaler: buffer smaller than its format",
+        ));
+    }
+    // the source pixel or line of a scaled one
+    let source_x = |x: usize| x * source_width / width;
+    let source_y = |y: usize| y * source_height / height;
+
+    if &layout.fourcc == b"YUYV" {
+        for (y, line) in scaled[..len].chunks_exact_mut(width * 2).enumerate() {
+            let source = &frame[source_y(y) * stride..];
+            for (x, pair) in line.chunks_exact_mut(4).enumerate() {
+                // the first pixel of the pair decides the chroma
+                let first = source_x(x * 2);
+                let second = source_x(x * 2 + 1);
+                let chroma = (first & !1) * 2;
+                pair.copy_from_slice(&[
+                    source[first * 2],
+                    source[chroma + 1],
+                    source[second * 2],
+                    source[chroma + 3],
+                ]);
+            }
+        }
+    } else {
+        let (luma, chroma) = scaled[..len].split_at_mut(width * height);
+        for (y, line) in luma.chunks_exact_mut(width).enumerate() {
+            let source = &frame[source_y(y) * stride..];
+            for (x, pixel) in line.iter_mut().enumerate() {
+                *pixel = source[source_x(x)];
+            }
+        }
+        // chroma has half the lines and pairs of Cb and Cr for two pixels
+        let source_chroma = &frame[stride * source_height..];
+        for (y, line) in chroma.chunks_exact_mut(width).enumerate() {
+            let source = &source_chroma[source_y(y * 2) / 2 * stride..];
+            for (x, pair) in line.chunks_exact_mut(2).enumerate() {
+                let offset = source_x(x * 2) & !1;
+                pair.copy_from_slice(&source[offset..offset + 2]);
+            }
+        }
+    }
+    Ok(len)
+}
+
+/// Scales and converts frames with a V4L2 M2M device.
+pub struct V4l2Scaler<'a> {
+    async_fd: AsyncFd<Arc<v4l::device::Handle>>,
+    device: MultiPlaneDevice,
+    /// streams of frames to scale and scaled frames, None until configured
+    streams: Option<(MmapStream<'a>, MmapStream<'a>)>,
+    /// bytes of the buffer of frames to scale
+    input_size: usize,
+    /// the layout of scaled frames the device took, None until configured
+    output_layout: Option<FrameLayout>,
+    /// whether the buffer of frames to scale was queued once, so it has to be dequeued
+    queued: bool,
+    streaming: bool,
+}
+
+impl<'a> V4l2Scaler<'a> {
+    pub fn new(device: usize) -> io::Result<Self> {
+        let device = MultiPlaneDevice::new(device)?;
+        let async_fd = AsyncFd::new(device.handle())?;
+
+        let caps = device.query_caps()?;
+        if !caps.capabilities.contains(Flags::VIDEO_M2M_MPLANE) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Scaler: M2M MPlane not supported",
+            ));
+        }
+        if !caps.capabilities.contains(Flags::STREAMING) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Scaler: Streaming not supported",
+            ));
+        }
+
+        Ok(Self {
+            async_fd,
+            device,
+            streams: None,
+            input_size: 0,
+            output_layout: None,
+            queued: false,
+            streaming: false,
+        })
+    }
+
+    /// Sets the formats of frames to scale and scaled frames, reallocating buffers.
+    ///
+    /// Fails if the device doesn't take frames of `input` or scale them to the size and
+    /// format of `output`. Scaled frames may have padded lines, as told by [Self::output_layout].
+    pub fn configure(&mut self, input: &FrameLayout, output: &FrameLayout) -> io::Result<()> {
+        // buffers are freed when the streams are dropped
+        self.streams = None;
+        self.output_layout = None;
+        self.queued = false;
+        self.streaming = false;
+
+        let format = Output::set_format(
+            &mut self.device,
+            &MultiPlaneFormat::single_plane(input.width, input.height, FourCC::new(&input.fourcc)),
+        )?;
+        // frames are copied to the device as they are
+        if (
+            format.width,
+            format.height,
+            format.plane_fmt[0].bytesperline,
+        ) != (input.width, input.height, input.stride)
+            || format.fourcc != FourCC::new(&input.fourcc)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Scaler: can't take frames in the format of the camera",
+            ));
+        }
+        let format = Capture::set_format(
+            &mut self.device,
+            &MultiPlaneFormat::single_plane(
+                output.width,
+                output.height,
+                FourCC::new(&output.fourcc),
+            ),
+        )?;
+        if (format.width, format.height) != (output.width, output.height)
+            || format.fourcc != FourCC::new(&output.fourcc)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "Scaler: can't scale to {}x{} {}",
+                    output.width,
+                    output.height,
+                    String::from_utf8_lossy(&output.fourcc)
+                ),
+            ));
+        }
+        let output_layout = FrameLayout {
+            stride: format.plane_fmt[0].bytesperline,
+            ..*output
+        };
+
+        let mut input_stream = MmapStream::with_buffers(&self.device, Type::VideoOutputMplane, 1)?;
+        let (buffers, _meta, _planes) = OutputStream::get(&mut input_stream, 0)?;
+        self.input_size = buffers[0].len();
+        let mut output_stream =
+            MmapStream::with_buffers(&self.device, Type::VideoCaptureMplane, 1)?;
+        CaptureStream::queue(&mut output_stream, 0)?;
+        self.streams = Some((input_stream, output_stream));
+        self.output_layout = Some(output_layout);
+        Ok(())
+    }
+
+    /// The layout of scaled frames, None until configured
+    pub fn output_layout(&self) -> Option<FrameLayout> {
+        self.output_layout
+    }
+
+    /// Scales a frame into `scaled`.
+    pub async fn scale(&mut self, frame: &[u8], scaled: &mut Vec<u8>) -> io::Result<()> {
+        let read_write: Interest = Interest::WRITABLE | Interest::READABLE;
+        let Some((input_stream, output_stream)) = &mut self.streams else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Scaler: not configured",
+            ));
+        };
+        // checked before taking the buffer from the device, which only gets it back queued
+        if frame.len() > self.input_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Scaler: frame larger than buffer",
+            ));
+        }
+
+        let index = if self.queued {
+            self.async_fd
+                .async_io(read_write, |_| OutputStream::dequeue(input_stream))
+                .await?
+        } else {
+            self.queued = true;
+            0
+        };
+        let (buffers, _meta, planes) = OutputStream::get(input_stream, index)?;
+        buffers[0][..frame.len()].copy_from_slice(frame);
+        planes[0].bytesused = frame.len() as u32;
+        OutputStream::queue(input_stream, index)?;
+
+        if !self.streaming {
+            input_stream.start()?;
+            output_stream.start()?;
+            self.streaming = true;
+        }
+
+        let index = self
+            .async_fd
+            .async_io(read_write, |_| CaptureStream::dequeue(output_stream))
+            .await?;
+        let (buffers, _meta, planes) = CaptureStream::get(output_stream, index)?;
+        scaled.clear();
+        scaled.extend_from_slice(&buffers[0][..planes[0].bytesused as usize]);
+        CaptureStream::queue(output_stream, index)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! Signaling of the session over a WebSocket of the control server, with trickle ICE.
+//!
+//! The viewer connects to `/signaling` and exchanges JSON messages tagged by `type`:
+//!
+//! - `{"type": "offer", "sdp": "..."}` from the viewer, answered by `{"type": "answer", ...}`.
+//! - `{"type": "candidate", "candidate": {"candidate": "...", "sdpMid": "0", "sdpMLineIndex": 0}}`
+//!   both ways for each candidate as it is gathered, like `RTCIceCandidate.toJSON()`.
+//!   `"candidate": null` ends the candidates.
+//!
+//! A connection gets the messages sent before it connected first, so a viewer which
+//! reconnects doesn't miss the answer.
+
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SignalingMessage {
+    Offer {
+        sdp: String,
+    },
+    Answer {
+        sdp: String,
+    },
+    /// a candidate, or None after the last one
+    Candidate {
+        candidate: Option<RTCIceCandidateInit>,
+    },
+}
+
+/// Passes messages between the WebSocket connections and the peer connection.
+#[derive(Clone)]
+pub struct SignalingHandle {
+    received: mpsc::Sender<SignalingMessage>,
+    sent: broadcast::Sender<SignalingMessage>,
+    /// the answer and the candidates sent so far, for connections to come
+    history: Arc<Mutex<Vec<SignalingMessage>>>,
+}
+
+impl SignalingHandle {
+    /// Creates a handle and the receiver of messages from the viewer.
+    pub fn new() -> (Self, mpsc::Receiver<SignalingMessage>) {
+        let (received, receiver) = mpsc::channel(32);
+        let (sent, _) = broadcast::channel(64);
+        let handle = Self {
+            received,
+            sent,
+            history: Arc::new(Mutex::new(Vec::new())),
+        };
+        (handle, receiver)
+    }
+
+    /// Sends a message to the connected viewers and keeps it for the ones connecting later.
+    pub fn send(&self, message: SignalingMessage) {
+        let mut history = self.history.lock().unwrap();
+        history.push(message.clone());
+        // no one may be connected
+        let _ = self.sent.send(message);
+    }
+
+    /// The messages sent so far and the receiver of the ones sent from now on,
+    /// for a connection
+    pub fn subscribe(&self) -> (Vec<SignalingMessage>, broadcast::Receiver<SignalingMessage>) {
+        // nothing is sent while the history is locked, so nothing is missed or repeated
+        let history = self.history.lock().unwrap();
+        (history.clone(), self.sent.subscribe())
+    }
+
+    /// Passes a message of a connection, false if the session doesn't take messages anymore.
+    pub async fn receive(&self, message: SignalingMessage) -> bool {
+        self.received.send(message).await.is_ok()
+    }
+}
@@ -0,0 +1,138 @@
+//! Still images of the live camera as JPEG.
+//!
+//! Requests are served by [crate::camera_capture::CameraCapture] with the next frame
+//! it captures, so the camera isn't opened twice.
+
+use jpeg_encoder::{ColorType, Encoder};
+use std::io;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+pub type SnapshotReply = oneshot::Sender<io::Result<Vec<u8>>>;
+
+/// Asks the camera for snapshots.
+#[derive(Clone)]
+pub struct SnapshotHandle {
+    sender: mpsc::Sender<SnapshotReply>,
+}
+
+/// The camera gives up a snapshot if it doesn't capture a frame in this time,
+/// e.g. while not streaming.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl SnapshotHandle {
+    /// Creates a handle and the receiver to give to the camera.
+    pub fn new() -> (Self, mpsc::Receiver<SnapshotReply>) {
+        let (sender, receiver) = mpsc::channel(8);
+        (Self { sender }, receiver)
+    }
+
+    /// Takes a JPEG of the next frame.
+    pub async fn take(&self) -> io::Result<Vec<u8>> {
+        let (reply, jpeg) = oneshot::channel();
+        self.sender
+            .send(reply)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "camera stopped"))?;
+        match tokio::time::timeout(SNAPSHOT_TIMEOUT, jpeg).await {
+            Ok(Ok(jpeg)) => jpeg,
+            Ok(Err(_)) => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "camera stopped",
+            )),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "camera is not capturing",
+            )),
+        }
+    }
+}
+
+/// A raw camera frame and its layout
+pub struct RawFrame {
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    /// bytes per line of the (first) plane
+    pub stride: u32,
+    pub fourcc: [u8; 4],
+}
+
+const JPEG_QUALITY: u8 = 85;
+
+/// Encodes a YUYV or NV12 frame as JPEG.
+pub fn encode_jpeg(frame: &RawFrame) -> io::Result<Vec<u8>> {
+    let (lines, line_size) = match &frame.fourcc {
+        b"YUYV" => (frame.height, frame.width * 2),
+        // chroma lines follow luma lines, with at least a pair of Cb and Cr
+        b"NV12" => (frame.height + frame.height.div_ceil(2), frame.width.max(2)),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Snapshot: only YUYV and NV12 are supported",
+            ))
+        }
+    };
+    if frame.stride < line_size || frame.data.len() < (frame.stride * lines) as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Snapshot: frame smaller than its format",
+        ));
+    }
+    let ycbcr = if &frame.fourcc == b"YUYV" {
+        yuyv_to_ycbcr(frame)
+    } else {
+        nv12_to_ycbcr(frame)
+    };
+
+    let mut jpeg = Vec::new();
+    Encoder::new(&mut jpeg, JPEG_QUALITY)
+        .encode(
+            &ycbcr,
+            frame.width as u16,
+            frame.height as u16,
+            ColorType::Ycbcr,
+        )
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(jpeg)
+}
+
+/// Interleaved Y, Cb and Cr of each pixel from packed 4:2:2
+fn yuyv_to_ycbcr(frame: &RawFrame) -> Vec<u8> {
+    let (width, height, stride) = (
+        frame.width as usize,
+        frame.height as usize,
+        frame.stride as usize,
+    );
+    let mut ycbcr = Vec::with_capacity(width * height * 3);
+    for line in frame.data.chunks(stride).take(height) {
+        for pair in line[..width * 2].chunks_exact(4) {
+            let (y0, u, y1, v) = (pair[0], pair[1], pair[2], pair[3]);
+            ycbcr.extend_from_slice(&[y0, u, v, y1, u, v]);
+        }
+    }
+    ycbcr
+}
+
+/// Interleaved Y, Cb and Cr of each pixel from planar 4:2:0 with interleaved chroma
+fn nv12_to_ycbcr(frame: &RawFrame) -> Vec<u8> {
+    let (width, height, stride) = (
+        frame.width as usize,
+        frame.height as usize,
+        frame.stride as usize,
+    );
+    let (luma, chroma) = frame.data.split_at(stride * height);
+    // the last pixel of an odd width takes the previous pair if its own is beyond the stride
+    let last_pair = (width.saturating_sub(1) & !1).min((stride - 2) & !1);
+    let mut ycbcr = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        let luma = &luma[y * stride..][..width];
+        let chroma = &chroma[(y / 2) * stride..][..stride];
+        for (x, &luma) in luma.iter().enumerate() {
+            let pair = (x & !1).min(last_pair);
+            let chroma = &chroma[pair..pair + 2];
+            ycbcr.extend_from_slice(&[luma, chroma[0], chroma[1]]);
+        }
+    }
+    ycbcr
+}
@@ -0,0 +1,427 @@
+//! Outputs of H.264 video received from the remote peer.
+
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::os::raw::c_void;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use v4l::buffer::Type;
+use v4l::capability::Flags;
+use v4l::device::MultiPlaneDevice;
+use v4l::format::MultiPlaneFormat;
+use v4l::io::traits::{CaptureStream, OutputStream, Stream};
+use v4l::prelude::*;
+use v4l::v4l2::vidioc;
+use v4l::v4l_sys::{v4l2_event, v4l2_event_subscription, V4L2_EVENT_SOURCE_CHANGE};
+use v4l::video::{Capture, Output};
+use v4l::{v4l2, Format, FourCC};
+
+/// Where access units of received H.264 video go to.
+pub trait VideoSink: Send {
+    /// Writes an access unit in Annex-B byte stream format.
+    fn write_access_unit(
+        &mut self,
+        access_unit: &[u8],
+    ) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Waits until the sink has frames of its own for [Self::forward], like a decoder
+    /// which finished some. Cancel safe, and never ready for sinks without.
+    fn ready(&mut self) -> impl Future<Output = io::Result<()>> + Send {
+        std::future::pending()
+    }
+
+    /// Handles the frames the sink was [Self::ready] for.
+    fn forward(&mut self) -> impl Future<Output = io::Result<()>> + Send {
+        std::future::ready(Ok(()))
+    }
+}
+
+/// Writes H.264 Annex-B byte stream, playable with e.g. `ffplay -f h264`.
+pub struct AnnexBFile {
+    writer: BufWriter<File>,
+}
+
+impl AnnexBFile {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+        })
+    }
+}
+
+impl VideoSink for AnnexBFile {
+    async fn write_access_unit(&mut self, access_unit: &[u8]) -> io::Result<()> {
+        self.writer.write_all(access_unit)?;
+        self.writer.flush()
+    }
+}
+
+/// Number of buffers queued to output devices
+const OUTPUT_BUFFERS: u32 = 4;
+
+/// Writes frames to a single-planar V4L2 output device like v4l2loopback.
+///
+/// The device takes frames of the format set here, which is H.264 for received
+/// access units as is or a raw format for frames from [V4l2Decoder].
+pub struct V4l2Output<'a> {
+    async_fd: AsyncFd<Arc<v4l::device::Handle>>,
+    device: Device,
+    /// None while the format changes
+    stream: Option<MmapStream<'a>>,
+    width: u32,
+    height: u32,
+    /// the number of buffers queued at least once, the rest are free to use
+    used_buffers: usize,
+    streaming: bool,
+}
+
+impl<'a> V4l2Output<'a> {
+    pub fn new(device: usize, width: u32, height: u32, fourcc: &[u8; 4]) -> io::Result<Self> {
+        let mut device = Device::new(device)?;
+        let async_fd = AsyncFd::with_interest(device.handle(), Interest::WRITABLE)?;
+
+        let caps = device.query_caps()?;
+        if !caps.capabilities.contains(Flags::VIDEO_OUTPUT) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Video Output: Output not supported",
+            ));
+        }
+        if !caps.capabilities.contains(Flags::STREAMING) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Video Output: Streaming not supported",
+            ));
+        }
+
+        Output::set_format(
+            &mut device,
+            &Format::new(width, height, FourCC::new(fourcc)),
+        )?;
+
+        let stream = MmapStream::with_buffers(&device, Type::VideoOutput, OUTPUT_BUFFERS)?;
+
+        Ok(Self {
+            async_fd,
+            device,
+            stream: Some(stream),
+            width,
+            height,
+            used_buffers: 0,
+            streaming: false,
+        })
+    }
+
+    /// Sets the size of frames, reallocating buffers if it changes.
+    pub fn resize(&mut self, width: u32, height: u32) -> io::Result<()> {
+        if (width, height) == (self.width, self.height) {
+            return Ok(());
+        }
+        // buffers are freed when the stream is dropped, which stops it
+        self.stream = None;
+        self.used_buffers = 0;
+        self.streaming = false;
+
+        let fourcc = Output::format(&self.device)?.fourcc;
+        Output::set_format(&mut self.device, &Format::new(width, height, fourcc))?;
+        self.stream = Some(MmapStream::with_buffers(
+            &self.device,
+            Type::VideoOutput,
+            OUTPUT_BUFFERS,
+        )?);
+        (self.width, self.height) = (width, height);
+        Ok(())
+    }
+
+    /// Queues a frame, waiting for the device to release a buffer if all are queued.
+    pub async fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        let Some(stream) = &mut self.stream else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Video Output: no buffers",
+            ));
+        };
+        let index = if self.used_buffers < OUTPUT_BUFFERS as usize {
+            self.used_buffers += 1;
+            self.used_buffers - 1
+        } else {
+            self.async_fd
+                .async_io(Interest::WRITABLE, |_| OutputStream::dequeue(stream))
+                .await?
+        };
+
+        let (buffers, meta, _planes) = OutputStream::get(stream, index)?;
+        let buffer = &mut buffers[0];
+        if frame.len() > buffer.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Video Output: frame larger than buffer",
+            ));
+        }
+        buffer[..frame.len()].copy_from_slice(frame);
+        meta.bytesused = frame.len() as u32;
+        OutputStream::queue(stream, index)?;
+
+        // start streaming with the first frame queued
+        if !self.streaming {
+            stream.start()?;
+            self.streaming = true;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> VideoSink for V4l2Output<'a> {
+    async fn write_access_unit(&mut self, access_unit: &[u8]) -> io::Result<()> {
+        self.write_frame(access_unit).await
+    }
+}
+
+impl<'a> Drop for V4l2Output<'a> {
+    fn drop(&mut self) {
+        if let (true, Some(stream)) = (self.streaming, &mut self.stream) {
+            let _ = stream.stop();
+        }
+    }
+}
+
+/// Decodes H.264 with a V4L2 M2M decoder and writes decoded frames to a [V4l2Output].
+///
+/// Decoded frames are forwarded as soon as the decoder finished them, see [VideoSink::ready].
+/// When the decoder finds another resolution in the stream, the buffers of decoded frames
+/// and of the output are reallocated for it.
+pub struct V4l2Decoder<'a> {
+    decoder_async_fd: AsyncFd<Arc<v4l::device::Handle>>,
+    decoder: MultiPlaneDevice,
+    coded_stream: MmapStream<'a>,
+    /// None while the resolution changes
+    decoded_stream: Option<MmapStream<'a>>,
+    decoded_fourcc: [u8; 4],
+    /// the number of coded buffers queued at least once, the rest are free to use
+    used_coded_buffers: usize,
+    streaming: bool,
+    output: V4l2Output<'a>,
+}
+
+impl<'a> V4l2Decoder<'a> {
+    pub fn new(
+        decoder_device: usize,
+        output: V4l2Output<'a>,
+        width: u32,
+        height: u32,
+        decoded_fourcc: &[u8; 4],
+    ) -> io::Result<Self> {
+        let mut decoder = MultiPlaneDevice::new(decoder_device)?;
+        let decoder_async_fd = AsyncFd::new(decoder.handle())?;
+
+        let decoder_caps = decoder.query_caps()?;
+        if !decoder_caps.capabilities.contains(Flags::VIDEO_M2M_MPLANE) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Decoder: M2M MPlane not supported",
+            ));
+        }
+        if !decoder_caps.capabilities.contains(Flags::STREAMING) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Decoder: Streaming not supported",
+            ));
+        }
+
+        Output::set_format(
+            &mut decoder,
+            &MultiPlaneFormat::single_plane(width, height, FourCC::new(b"H264")),
+        )?;
+        Capture::set_format(
+            &mut decoder,
+            &MultiPlaneFormat::single_plane(width, height, FourCC::new(decoded_fourcc)),
+        )?;
+
+        subscribe_source_change(&decoder)?;
+
+        let coded_stream =
+            MmapStream::with_buffers(&decoder, Type::VideoOutputMplane, OUTPUT_BUFFERS)?;
+        let decoded_stream = decoded_stream(&decoder)?;
+
+        Ok(Self {
+            decoder_async_fd,
+            decoder,
+            coded_stream,
+            decoded_stream: Some(decoded_stream),
+            decoded_fourcc: *decoded_fourcc,
+            used_coded_buffers: 0,
+            streaming: false,
+            output,
+        })
+    }
+
+    /// Moves frames the decoder has finished to the output device,
+    /// then follows a change of the resolution.
+    async fn forward_decoded(&mut self) -> io::Result<()> {
+        if let Some(decoded_stream) = &mut self.decoded_stream {
+            loop {
+                // the device is non-blocking, so this fails with WouldBlock if no frame is ready,
+                // and with BrokenPipe after the last frame before a change of the resolution
+                let index = match CaptureStream::dequeue(decoded_stream) {
+                    Ok(index) => index,
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::BrokenPipe
+                        ) =>
+                    {
+                        break
+                    }
+                    Err(e) => return Err(e),
+                };
+                let (buffers, _meta, planes) = CaptureStream::get(decoded_stream, index)?;
+                let frame = &buffers[0][..planes[0].bytesused as usize];
+                // the last frame before a change of the resolution may be empty
+                if !frame.is_empty() {
+                    self.output.write_frame(frame).await?;
+                }
+                CaptureStream::queue(decoded_stream, index)?;
+            }
+        }
+        if dequeue_source_change(&self.decoder)? {
+            self.change_resolution()?;
+        }
+        Ok(())
+    }
+
+    /// Sets up the buffers of decoded frames and the output for the resolution
+    /// the decoder found in the stream.
+    fn change_resolution(&mut self) -> io::Result<()> {
+        // buffers are freed when the stream is dropped, which stops it
+        self.decoded_stream = None;
+
+        let format = Capture::format(&self.decoder)?;
+        let (width, height) = (format.width, format.height);
+        println!("Decoder: resolution changed to {width}x{height}");
+        Capture::set_format(
+            &mut self.decoder,
+            &MultiPlaneFormat::single_plane(width, height, FourCC::new(&self.decoded_fourcc)),
+        )?;
+        let mut decoded_stream = decoded_stream(&self.decoder)?;
+        if self.streaming {
+            decoded_stream.start()?;
+        }
+        self.decoded_stream = Some(decoded_stream);
+        self.output.resize(width, height)
+    }
+}
+
+/// Allocates the buffers of decoded frames and queues them for the decoder.
+fn decoded_stream<'a>(decoder: &MultiPlaneDevice) -> io::Result<MmapStream<'a>> {
+    let mut stream = MmapStream::with_buffers(decoder, Type::VideoCaptureMplane, OUTPUT_BUFFERS)?;
+    for i in 0..OUTPUT_BUFFERS {
+        CaptureStream::queue(&mut stream, i as usize)?;
+    }
+    Ok(stream)
+}
+
+/// Has the decoder send an event when it finds another resolution in the stream.
+fn subscribe_source_change(decoder: &MultiPlaneDevice) -> io::Result<()> {
+    let mut subscription: v4l2_event_subscription = unsafe { std::mem::zeroed() };
+    subscription.type_ = V4L2_EVENT_SOURCE_CHANGE;
+    unsafe {
+        v4l2::ioctl(
+            decoder.handle().fd(),
+            vidioc::VIDIOC_SUBSCRIBE_EVENT,
+            &mut subscription as *mut _ as *mut c_void,
+        )
+    }
+}
+
+/// Takes the pending events of the decoder, returning whether the resolution changed.
+fn dequeue_source_change(decoder: &MultiPlaneDevice) -> io::Result<bool> {
+    let mut changed = false;
+    loop {
+        let mut event: v4l2_event = unsafe { std::mem::zeroed() };
+        let result = unsafe {
+            v4l2::ioctl(
+                decoder.handle().fd(),
+                vidioc::VIDIOC_DQEVENT,
+                &mut event as *mut _ as *mut c_void,
+            )
+        };
+        match result {
+            Ok(()) => changed |= event.type_ == V4L2_EVENT_SOURCE_CHANGE,
+            // no more events
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(changed),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+impl<'a> VideoSink for V4l2Decoder<'a> {
+    async fn write_access_unit(&mut self, access_unit: &[u8]) -> io::Result<()> {
+        if self.streaming {
+            self.forward_decoded().await?;
+        }
+
+        let index = if self.used_coded_buffers < OUTPUT_BUFFERS as usize {
+            self.used_coded_buffers += 1;
+            self.used_coded_buffers - 1
+        } else {
+            self.decoder_async_fd
+                .async_io(Interest::WRITABLE, |_| {
+                    OutputStream::dequeue(&mut self.coded_stream)
+                })
+                .await?
+        };
+
+        let (buffers, _meta, planes) = OutputStream::get(&mut self.coded_stream, index)?;
+        let buffer = &mut buffers[0];
+        if access_unit.len() > buffer.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Decoder: access unit larger than buffer",
+            ));
+        }
+        buffer[..access_unit.len()].copy_from_slice(access_unit);
+        planes[0].bytesused = access_unit.len() as u32;
+        OutputStream::queue(&mut self.coded_stream, index)?;
+
+        // start decoding with the first access unit queued
+        if !self.streaming {
+            self.coded_stream.start()?;
+            if let Some(decoded_stream) = &mut self.decoded_stream {
+                decoded_stream.start()?;
+            }
+            self.streaming = true;
+        }
+        Ok(())
+    }
+
+    async fn ready(&mut self) -> io::Result<()> {
+        // the device reports an error until it decodes
+        if !self.streaming {
+            return std::future::pending().await;
+        }
+        let mut guard = self.decoder_async_fd.readable().await?;
+        // cleared before forwarding the frames up to WouldBlock, so a frame decoded
+        // meanwhile makes it ready again
+        guard.clear_ready();
+        Ok(())
+    }
+
+    async fn forward(&mut self) -> io::Result<()> {
+        self.forward_decoded().await
+    }
+}
+
+impl<'a> Drop for V4l2Decoder<'a> {
+    fn drop(&mut self) {
+        if self.streaming {
+            let _ = self.coded_stream.stop();
+            if let Some(decoded_stream) = &mut self.decoded_stream {
+                let _ = decoded_stream.stop();
+            }
+        }
+    }
+}
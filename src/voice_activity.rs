@@ -0,0 +1,159 @@
+//! Audio level and voice activity of captured audio, sent with the RFC 6464 header extension.
+
+use std::time::Duration;
+
+/// The level of a frame in -dBov and whether it contains voice, as in RFC 6464.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AudioLevel {
+    /// 0 (loudest) to 127 (silent), the negated level in dBov
+    pub level: u8,
+    pub voice: bool,
+}
+
+/// Voice activity changes of the captured audio.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VoiceActivityEvent {
+    /// voice started, with the level of the first voiced frame
+    Started(AudioLevel),
+    Stopped,
+}
+
+/// Energy based voice activity detector with an adaptive noise floor.
+pub struct VoiceActivityDetector {
+    /// estimated level of background noise in dBov
+    noise_floor: f32,
+    /// how long voice is still reported after the level falls
+    hangover: Duration,
+    voice: bool,
+}
+
+/// A frame is voice if it's this much louder than the noise floor (dB).
+const VOICE_THRESHOLD: f32 = 9.0;
+/// Frames quieter than this are never voice (dBov).
+const MIN_VOICE_LEVEL: f32 = -55.0;
+/// How fast the noise floor follows louder audio (dB per second)
+const NOISE_FLOOR_RISE: f32 = 3.0;
+/// How fast it follows while there is voice, so long speech isn't taken for noise,
+/// but louder noise which comes to stay eventually is
+const NOISE_FLOOR_RISE_IN_VOICE: f32 = 0.3;
+/// Keep reporting voice for this long to bridge pauses between words.
+const HANGOVER: Duration = Duration::from_millis(300);
+
+impl VoiceActivityDetector {
+    pub fn new() -> Self {
+        Self {
+            noise_floor: -60.0,
+            hangover: Duration::ZERO,
+            voice: false,
+        }
+    }
+
+    /// Measures a frame of interleaved PCM lasting `duration`.
+    pub fn analyze(&mut self, pcm: &[i16], duration: Duration) -> AudioLevel {
+        let dbov = level_dbov(pcm);
+
+        let rise = if self.voice {
+            NOISE_FLOOR_RISE_IN_VOICE
+        } else {
+            NOISE_FLOOR_RISE
+        };
+        // it falls to quieter audio at once and never rises above the audio
+        self.noise_floor = (self.noise_floor + rise * duration.as_secs_f32()).min(dbov);
+
+        if dbov > MIN_VOICE_LEVEL && dbov > self.noise_floor + VOICE_THRESHOLD {
+            self.hangover = HANGOVER;
+            self.voice = true;
+        } else if self.hangover > duration {
+            self.hangover -= duration;
+        } else {
+            self.hangover = Duration::ZERO;
+            self.voice = false;
+        }
+
+        AudioLevel {
+            level: (-dbov).round().clamp(0.0, 127.0) as u8,
+            voice: self.voice,
+        }
+    }
+}
+
+impl Default for VoiceActivityDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// RMS level relative to the overload point of 16-bit PCM (RFC 6464 section 3)
+fn level_dbov(pcm: &[i16]) -> f32 {
+    if pcm.is_empty() {
+        return -127.0;
+    }
+    let sum = pcm.iter().map(|&x| (x as f64).powi(2)).sum::<f64>();
+    let rms = (sum / pcm.len() as f64).sqrt() / 32768.0;
+    if rms == 0.0 {
+        -127.0
+    } else {
+        (20.0 * rms.log10()) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(20);
+
+    /// A 20 ms mono frame at `dbov`
+    fn frame(dbov: f32) -> Vec<i16> {
+        let amplitude = (32768.0 * 10f32.powf(dbov / 20.0)) as i16;
+        (0..960)
+            .map(|i| if i % 2 == 0 { amplitude } else { -amplitude })
+            .collect()
+    }
+
+    /// Whether each of `count` frames at `dbov` was voice
+    fn analyze(detector: &mut VoiceActivityDetector, dbov: f32, count: usize) -> Vec<bool> {
+        let pcm = frame(dbov);
+        (0..count)
+            .map(|_| detector.analyze(&pcm, FRAME).voice)
+            .collect()
+    }
+
+    #[test]
+    fn measures_level() {
+        let mut detector = VoiceActivityDetector::new();
+        assert_eq!(detector.analyze(&frame(-20.0), FRAME).level, 20);
+        assert_eq!(detector.analyze(&[0; 960], FRAME).level, 127);
+    }
+
+    #[test]
+    fn long_speech_stays_voice() {
+        let mut detector = VoiceActivityDetector::new();
+        assert!(!analyze(&mut detector, -60.0, 50).contains(&true));
+        // a minute of speech
+        assert!(!analyze(&mut detector, -20.0, 3000).contains(&false));
+        assert!(detector.noise_floor < -20.0 - VOICE_THRESHOLD);
+    }
+
+    #[test]
+    fn noise_floor_stays_below_level() {
+        let mut detector = VoiceActivityDetector::new();
+        analyze(&mut detector, -60.0, 50);
+        analyze(&mut detector, -50.0, 3000);
+        assert!(detector.noise_floor <= -50.0);
+        // and follows quieter audio at once
+        analyze(&mut detector, -70.0, 1);
+        assert!(detector.noise_floor <= -70.0);
+    }
+
+    #[test]
+    fn voice_ends_after_hangover() {
+        let mut detector = VoiceActivityDetector::new();
+        analyze(&mut detector, -60.0, 50);
+        assert!(!analyze(&mut detector, -20.0, 50).contains(&false));
+        let hangover = (HANGOVER.as_millis() / FRAME.as_millis()) as usize;
+        let voice = analyze(&mut detector, -60.0, hangover + 1);
+        assert!(!voice[..hangover - 1].contains(&false));
+        assert!(!voice[hangover]);
+    }
+}